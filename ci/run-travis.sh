@@ -23,3 +23,5 @@ cargo build --example sms_get_token || die_example
 cargo build --example sms_send_verification_code || die_example
 
 cargo test
+# the integration tests run against the mock server
+cargo test --features mock
@@ -20,13 +20,16 @@ fn main() {
         Some(args[6].parse().unwrap())
     };
 
+    // validate locally before consuming a SMS token
+    let config = open189::SmsCodeConfig::prepared(phone.as_str(), code.as_str(), expire_time);
+    println!("config = {:?}", config);
+    let config = config.unwrap();
+
     let app = open189::Open189App::new(app_id, secret);
     let sms_token = app.sms_get_token(access_token);
     println!("sms token = {:?}", sms_token);
     let sms_token = sms_token.unwrap();
 
-    let config = open189::SmsCodeConfig::prepared(phone, code, expire_time);
-
     let result = app.sms_send_verification_code(access_token, &sms_token, config);
     println!("send result = {:?}", result);
 }
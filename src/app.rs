@@ -1,25 +1,134 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use hyper::client::Client;
-use hyper::client::IntoUrl;
-
-use url::Url;
 
+use super::clock::{Clock, SystemClock};
+use super::endpoints::Endpoints;
 use super::errors::*;
+use super::metrics::MetricsRecorder;
+use super::middleware::{Exchange, Middleware};
 use super::msg;
+use super::req::{self, SmsCodeConfig};
 use super::resp;
+use super::retry::RetryPolicy;
+use super::net;
 use super::net::Open189Client;
-
-const URL_SMS_TOKEN: &'static str = "http://api.189.cn/v2/dm/randcode/token";
-const URL_SMS_SEND_WITH_CODE: &'static str = "http://api.189.cn/v2/dm/randcode/sendSms";
-const URL_SMS_SEND_WITH_CALLBACK: &'static str = "http://api.189.cn/v2/dm/randcode/send";
+use super::oauth::AuthorizeUrlBuilder;
+use super::ratelimit::RateLimiter;
+use super::redact;
+use super::token::{MemoryTokenStore, TokenStore};
+use super::token_cache::{DEFAULT_REFRESH_MARGIN_SECS, TokenCache};
+use super::transport;
+use super::transport::{HttpRequest, Timeouts, Transport};
+use super::util;
 
 
 /// Client for the `open.189.cn` API.
-pub struct Open189App {
+///
+/// The client is generic over the HTTP [`Transport`] in use, which defaults to
+/// a Hyper client.
+///
+/// [`Transport`]: transport/trait.Transport.html
+pub struct Open189App<T: Transport = Client> {
+    app_id: String,
+    secret: String,
+    client: Open189Client<T>,
+    endpoints: Endpoints,
+    rate_limiter: Option<RateLimiter>,
+    tokens: TokenCache<Arc<TokenStore>>,
+}
+
+
+/// Builder for [`Open189App`] instances.
+///
+/// Use this when the defaults of the [`Open189App`] constructors don't fit.
+/// Everything but the app ID and secret is optional; the configuration is
+/// validated as a whole by [`build`], which reports problems as
+/// `ErrorKind::InvalidConfig` errors.
+///
+/// [`Open189App`]: struct.Open189App.html
+/// [`build`]: #method.build
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use open189::Open189AppBuilder;
+/// use open189::retry::RetryPolicy;
+/// use open189::transport::Timeouts;
+///
+/// let client = Open189AppBuilder::new("your app id here", "your app secret here")
+///     .api_base("http://staging.example.com/api/")
+///     .timeouts(Timeouts::new(Some(Duration::from_secs(5)), None, None))
+///     .user_agent("my-app/1.0")
+///     .retry_policy(RetryPolicy::new(3))
+///     .on_response(|exchange| {
+///         println!("{} {} took {:?}, res_code: {:?}",
+///                  exchange.request.method,
+///                  exchange.request.url.path(),
+///                  exchange.latency,
+///                  exchange.res_code);
+///     })
+///     .build()
+///     .unwrap();
+/// ```
+pub struct Open189AppBuilder<T: Transport = Client> {
     app_id: String,
     secret: String,
-    client: Open189Client,
+    transport: TransportSource<T>,
+    timeouts: Option<Timeouts>,
+    endpoints: Endpoints,
+    oauth_base: Option<String>,
+    api_base: Option<String>,
+    user_agent: Option<String>,
+    retry_policy: RetryPolicy,
+    clock: Arc<Clock>,
+    skew_correction: bool,
+    on_request: Option<RequestHook>,
+    on_response: Option<ResponseHook>,
+    middleware: Vec<Arc<Middleware>>,
+    mask_phone_numbers: bool,
+    metrics: Option<Arc<MetricsRecorder>>,
+}
+
+
+/// Hook called with every request right before it's sent.
+type RequestHook = Arc<Fn(&HttpRequest) + Send + Sync>;
+/// Hook called with the outcome of every request.
+type ResponseHook = Arc<Fn(&Exchange) + Send + Sync>;
+
+
+/// The innermost middleware layer, calling the builder's hooks.
+struct Hooks {
+    on_request: Option<RequestHook>,
+    on_response: Option<ResponseHook>,
+}
+
+
+impl Middleware for Hooks {
+    fn before_send(&self, request: &mut HttpRequest) {
+        if let Some(ref hook) = self.on_request {
+            hook(&*request);
+        }
+    }
+
+    fn after_response(&self, exchange: &Exchange) {
+        if let Some(ref hook) = self.on_response {
+            hook(exchange);
+        }
+    }
+}
+
+
+/// Where the builder gets its transport from.
+enum TransportSource<T> {
+    /// A transport made once the timeouts are known.
+    Default(fn(&Timeouts) -> T),
+    /// A transport provided by the user.
+    Custom(T),
 }
 
 
@@ -67,10 +176,51 @@ impl Open189App {
     /// # }
     /// ```
     pub fn with_client<S: AsRef<str>>(app_id: S, secret: S, client: Client) -> Open189App {
+        Open189App::with_transport(app_id, secret, client)
+    }
+
+    /// Construct a client instance with a Hyper client honoring the given
+    /// connect, read and write timeouts.
+    ///
+    /// Timeouts are reported as `ErrorKind::Timeout` errors, telling connect
+    /// timeouts apart from the others.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use open189::Open189App;
+    /// use open189::transport::Timeouts;
+    ///
+    /// let timeouts = Timeouts::new(Some(Duration::from_secs(5)),
+    ///                              Some(Duration::from_secs(10)),
+    ///                              Some(Duration::from_secs(10)));
+    /// let client = Open189App::with_timeouts("your app id here", "your app secret here", timeouts);
+    /// ```
+    pub fn with_timeouts<S: AsRef<str>>(app_id: S, secret: S, timeouts: Timeouts) -> Open189App {
+        Open189App::with_client(app_id, secret, transport::hyper_client(&timeouts))
+    }
+}
+
+
+impl<T: Transport> Open189App<T> {
+    /// Construct a client instance with the provided HTTP transport.
+    ///
+    /// This is the most general constructor; use it if you'd like to swap out
+    /// the HTTP stack entirely, or to plug in a fake transport for testing.
+    /// See the [`transport`] module for details.
+    ///
+    /// [`transport`]: transport/index.html
+    pub fn with_transport<S: AsRef<str>>(app_id: S, secret: S, transport: T) -> Open189App<T> {
         Open189App {
             app_id: app_id.as_ref().to_string(),
             secret: secret.as_ref().to_string(),
-            client: Open189Client::new(client),
+            client: Open189Client::new(transport),
+            endpoints: Default::default(),
+            rate_limiter: None,
+            tokens: TokenCache::new(Arc::new(MemoryTokenStore::new()),
+                                    Duration::from_secs(DEFAULT_REFRESH_MARGIN_SECS)),
         }
     }
 
@@ -108,6 +258,151 @@ impl Open189App {
         &self.secret
     }
 
+    /// Get the endpoints requests are sent to.
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    /// Set the endpoints requests are sent to.
+    ///
+    /// The production endpoints are used by default.
+    pub fn set_endpoints(&mut self, endpoints: Endpoints) {
+        self.endpoints = endpoints;
+    }
+
+    /// Run the closure with every request made in it, on the current thread,
+    /// bounded by a deadline `timeout` from now.
+    ///
+    /// Requests are given the time left until the deadline as their connect,
+    /// read and write timeouts, failing with an
+    /// `ErrorKind::Timeout(TimeoutKind::Deadline)` error once it passes, and
+    /// no retries are attempted past the deadline. Custom transports only
+    /// honor this if they implement [`Transport::send_timeout`]. Deadlines
+    /// nest, the inner ones only ever tightening the bound, so they propagate
+    /// into helpers making several requests.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// # fn foo(client: &::open189::Open189App) -> ::open189::errors::Result<()> {
+    /// let access_token = client.with_deadline(Duration::from_secs(3), |client| {
+    ///     client.get_access_token_cc()
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`Transport::send_timeout`]: transport/trait.Transport.html#method.send_timeout
+    pub fn with_deadline<F, R>(&self, timeout: Duration, f: F) -> R
+        where F: FnOnce(&Self) -> R
+    {
+        net::with_deadline(timeout, || f(self))
+    }
+
+    /// Get the policy for retrying failed requests.
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        self.client.retry_policy()
+    }
+
+    /// Set the policy for retrying failed requests.
+    ///
+    /// No request is retried by default. See the [`retry`] module for
+    /// details.
+    ///
+    /// [`retry`]: retry/index.html
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.client.set_retry_policy(retry_policy);
+    }
+
+    /// Get the clock requests are timestamped with.
+    ///
+    /// Cached access tokens are aged by this clock as well.
+    pub fn clock(&self) -> &Clock {
+        self.client.clock()
+    }
+
+    /// Set the clock requests are timestamped with.
+    ///
+    /// Any skew measured against the previous clock is discarded. See the
+    /// [`clock`] module for details.
+    ///
+    /// [`clock`]: clock/index.html
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.client.set_clock(Arc::new(clock));
+    }
+
+    /// Enable or disable correction for skew from the API server's clock.
+    ///
+    /// When enabled, the skew is measured from the `Date` header of every
+    /// response and applied to the timestamps of subsequent requests. A
+    /// request rejected with `ApiErrorCode::TimestampExpired` is re-signed
+    /// with the corrected time and retried once, whatever the retry policy.
+    /// Disabled by default.
+    pub fn set_skew_correction(&mut self, enabled: bool) {
+        self.client.set_skew_correction(enabled);
+    }
+
+    /// Add a layer to the end of the middleware chain.
+    ///
+    /// See the [`middleware`] module for details.
+    ///
+    /// [`middleware`]: middleware/index.html
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, layer: M) {
+        self.client.push_middleware(Arc::new(layer));
+    }
+
+    /// Set whether phone numbers are masked in log messages.
+    ///
+    /// The client logs its requests through the `log` crate. Secrets, tokens,
+    /// signatures and verification codes are never logged; phone numbers are
+    /// logged with their middle digits masked, e.g. `138****1234`, unless
+    /// masking is disabled here.
+    pub fn set_mask_phone_numbers(&mut self, mask: bool) {
+        self.client.set_mask_phone_numbers(mask);
+    }
+
+    /// Report the metrics of every API call to the recorder.
+    ///
+    /// See the [`metrics`] module for details.
+    ///
+    /// [`metrics`]: metrics/index.html
+    pub fn set_metrics_recorder<M: MetricsRecorder + 'static>(&mut self, recorder: M) {
+        self.client.set_metrics_recorder(Arc::new(recorder));
+    }
+
+    /// Get the rate limiter in front of SMS sending, if any.
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    /// Install or remove the rate limiter in front of SMS sending.
+    ///
+    /// With a rate limiter installed, [`sms_send_verification_code`] rejects
+    /// sends exceeding the limits with `ErrorKind::RateLimited` before any
+    /// request is made. Sends passing the check are counted whether or not
+    /// the request then succeeds. See the [`ratelimit`] module for details.
+    ///
+    /// [`sms_send_verification_code`]: #method.sms_send_verification_code
+    /// [`ratelimit`]: ratelimit/index.html
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<RateLimiter>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    /// Set the store caching the access token of [`send_verification_code`].
+    ///
+    /// The token is kept in memory by default. Use a store shared between
+    /// processes, such as a [`FileTokenStore`], to share the token and its
+    /// refreshes between them. Any token cached so far is forgotten.
+    ///
+    /// [`send_verification_code`]: #method.send_verification_code
+    /// [`FileTokenStore`]: token/struct.FileTokenStore.html
+    pub fn set_token_store<S: TokenStore + 'static>(&mut self, store: S) {
+        self.tokens = TokenCache::new(Arc::new(store),
+                                      Duration::from_secs(DEFAULT_REFRESH_MARGIN_SECS));
+    }
+
     /// Request a user-independent access token with the Client Credentials flow.
     ///
     /// As a best practice, you should utilize the API considerately and avoid
@@ -125,145 +420,137 @@ impl Open189App {
     /// # }
     /// ```
     pub fn get_access_token_cc(&self) -> Result<msg::AccessToken> {
-        let mut params = HashMap::new();
-        params.insert("grant_type", "client_credentials".to_string());
+        let path = req::PATH_ACCESS_TOKEN;
+        let url = self.endpoints.oauth_url(path)?;
+        let params = req::access_token_cc_params();
         self.client
-            .perform_access_token_req::<_, resp::AccessTokenResponse>(self.app_id(),
-                                                                      self.secret(),
-                                                                      params)
+            .perform_access_token_req::<_, _, resp::AccessTokenResponse>(self.app_id(),
+                                                                         self.secret(),
+                                                                         path,
+                                                                         url,
+                                                                         params)
     }
 
-    /// Request a token for use in the SMS sending API.
+    /// Start building the URL for users to authorize the app.
     ///
-    /// An access token is required; you can get one with the [`get_access_token_cc`]
-    /// method.
+    /// Redirect the user to the resulting URL; after the user grants access,
+    /// the API redirects back to `redirect_uri` with a `code`, which can be
+    /// exchanged for an access token with [`get_access_token_ac`]. See the
+    /// [`oauth`] module for details.
     ///
-    /// [`get_access_token_cc`]: #method.get_access_token_cc
+    /// [`get_access_token_ac`]: #method.get_access_token_ac
+    /// [`oauth`]: oauth/index.html
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use open189::Open189App;
+    ///
+    /// let client = Open189App::new("your app id here", "your app secret here");
+    /// let url = client.authorize_url("https://example.com/oauth/callback")
+    ///     .state("some random nonce")
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn authorize_url<S: AsRef<str>>(&self, redirect_uri: S) -> AuthorizeUrlBuilder {
+        AuthorizeUrlBuilder::new(self.app_id(), redirect_uri.as_ref()).endpoints(&self.endpoints)
+    }
+
+    /// Exchange an authorization code for a user access token, with the
+    /// Authorization Code flow.
+    ///
+    /// The `redirect_uri` must be the same as the one used to build the
+    /// authorization URL.
     ///
     /// # Examples
     ///
     /// ```no_run
-    /// # fn fetch_cached_access_token() -> &'static str { "dummy" }
     /// # fn foo(client: &::open189::Open189App) -> ::open189::errors::Result<()> {
-    /// let access_token = fetch_cached_access_token();
-    /// let sms_token = client.sms_get_token(access_token)?;
+    /// # let code = "dummy";
+    /// let token = client.get_access_token_ac(code, "https://example.com/oauth/callback")?;
+    /// println!("user {} authorized the app", token.open_id);
     /// # Ok(())
     /// # }
     /// ```
-    pub fn sms_get_token<S: AsRef<str>>(&self, access_token: S) -> Result<String> {
-        let params = HashMap::new();
-        self.client.get_sync::<_, _, resp::SmsTokenResponse>(self.app_id(),
-                                                             self.secret(),
-                                                             access_token.as_ref(),
-                                                             URL_SMS_TOKEN,
-                                                             params)
+    pub fn get_access_token_ac<S: AsRef<str>>(&self,
+                                              code: S,
+                                              redirect_uri: S)
+                                              -> Result<msg::UserAccessToken> {
+        let params = req::access_token_ac_params(code.as_ref(), redirect_uri.as_ref());
+        let path = req::PATH_ACCESS_TOKEN;
+        let url = self.endpoints.oauth_url(path)?;
+        self.client
+            .perform_access_token_req::<_, _, resp::UserAccessTokenResponse>(self.app_id(),
+                                                                             self.secret(),
+                                                                             path,
+                                                                             url,
+                                                                             params)
     }
-}
-
-
-/// Configuration for sending of locally generated SMS verification code.
-///
-/// This struct is not meant to be used directly; see [`SmsCodeConfig::prepared`]
-/// instead.
-///
-/// [`SmsCodeConfig::prepared`]: enum.SmsCodeConfig.html#method.prepared
-pub struct PreparedSmsCode<'a> {
-    phone: &'a str,
-    code: &'a str,
-    expire_time: Option<usize>,
-}
-
-
-/// Configuration for sending of API-generated SMS verification code.
-///
-/// This struct is not meant to be used directly; see [`SmsCodeConfig::callback`]
-/// instead.
-///
-/// [`SmsCodeConfig::callback`]: enum.SmsCodeConfig.html#method.callback
-pub struct CallbackSmsCode<'a> {
-    phone: &'a str,
-    url: Url,
-    expire_time: Option<usize>,
-}
 
-
-/// Configuration for SMS verification code.
-pub enum SmsCodeConfig<'a> {
-    /// Code is generated locally, ready to be sent.
-    Prepared(PreparedSmsCode<'a>),
-    /// Code is to be generated remotely by the API server, and sent back to the
-    /// callback URL provided.
-    Callback(CallbackSmsCode<'a>),
-}
-
-
-impl<'a> SmsCodeConfig<'a> {
-    /// Construct the parameters for sending pre-generated verification code.
-    ///
-    /// The code should consist of 6 digits only. Everything else would be rejected
-    /// by the API anyway, so the validation is done locally before firing the
-    /// actual request.
+    /// Request a new user access token with the refresh token obtained
+    /// alongside the previous one.
     ///
-    /// Expiry time is optional and seems purely informative, given it's just
-    /// another integer formatted into the fixed SMS template. The value is
-    /// expected to be in minutes, as suggested by the wording of the template.
-    /// Defaults to 2 minutes if not specified.
+    /// This lets you keep acting on behalf of the user after the access token
+    /// expires, without asking the user to authorize the app again. A new
+    /// refresh token is returned as well; use it for the next refresh.
     ///
     /// # Examples
     ///
+    /// ```no_run
+    /// # fn foo(client: &::open189::Open189App,
+    /// #        token: ::open189::msg::UserAccessToken)
+    /// #        -> ::open189::errors::Result<()> {
+    /// let token = client.refresh_access_token(&token.refresh_token)?;
+    /// // store the new token pair somewhere!
+    /// # Ok(())
+    /// # }
     /// ```
-    /// use open189::SmsCodeConfig;
-    ///
-    /// // use default expiry time
-    /// let config = SmsCodeConfig::prepared("12345678901", "234567", None);
-    ///
-    /// // manually specify an expiry time of 5 min
-    /// let config = SmsCodeConfig::prepared("12345678901", "234567", Some(5));
-    /// ```
-    pub fn prepared(phone: &'a str,
-                    code: &'a str,
-                    expire_time: Option<usize>)
-                    -> SmsCodeConfig<'a> {
-        SmsCodeConfig::Prepared(PreparedSmsCode {
-            phone: phone,
-            code: code,
-            expire_time: expire_time,
-        })
+    pub fn refresh_access_token<S: AsRef<str>>(&self,
+                                               refresh_token: S)
+                                               -> Result<msg::UserAccessToken> {
+        let params = req::refresh_token_params(refresh_token.as_ref());
+        let path = req::PATH_ACCESS_TOKEN;
+        let url = self.endpoints.oauth_url(path)?;
+        self.client
+            .perform_access_token_req::<_, _, resp::UserAccessTokenResponse>(self.app_id(),
+                                                                             self.secret(),
+                                                                             path,
+                                                                             url,
+                                                                             params)
     }
 
-    /// Construct the parameters for sending remotely-generated verification code.
-    ///
-    /// Instead of providing the code yourself, the API would choose one for you,
-    /// and notify you by `POST`-ing to the callback URL you provided. The URL is
-    /// validated on construction and may fail, in which case an `Err` would be
-    /// returned.
+    /// Request a token for use in the SMS sending API.
     ///
-    /// Expiry time is interpreted the same way as [above].
+    /// An access token is required; you can get one with the [`get_access_token_cc`]
+    /// method.
     ///
-    /// [above]: #method.prepared
+    /// [`get_access_token_cc`]: #method.get_access_token_cc
     ///
     /// # Examples
     ///
+    /// ```no_run
+    /// # fn fetch_cached_access_token() -> &'static str { "dummy" }
+    /// # fn foo(client: &::open189::Open189App) -> ::open189::errors::Result<()> {
+    /// let access_token = fetch_cached_access_token();
+    /// let sms_token = client.sms_get_token(access_token)?;
+    /// # Ok(())
+    /// # }
     /// ```
-    /// use open189::SmsCodeConfig;
-    ///
-    /// let url = "https://api.example.com/v1/callback/sms";
-    /// let config = SmsCodeConfig::callback("12345678901", url, None).unwrap();
-    /// ```
-    pub fn callback<U: IntoUrl>(phone: &'a str,
-                                callback_url: U,
-                                expire_time: Option<usize>)
-                                -> Result<SmsCodeConfig<'a>> {
-        Ok(SmsCodeConfig::Callback(CallbackSmsCode {
-            phone: phone,
-            url: callback_url.into_url()?,
-            expire_time: expire_time,
-        }))
+    pub fn sms_get_token<S: AsRef<str>>(&self, access_token: S) -> Result<String> {
+        let path = req::PATH_SMS_TOKEN;
+        let url = self.endpoints.api_url(path)?;
+        let params = HashMap::new();
+        self.client.get_sync::<_, _, resp::SmsTokenResponse>(self.app_id(),
+                                                             self.secret(),
+                                                             access_token.as_ref(),
+                                                             path,
+                                                             url,
+                                                             params)
     }
 }
 
 
-impl Open189App {
+impl<T: Transport> Open189App<T> {
     /// Send a verification code with SMS.
     ///
     /// An access token is required; you can get one with the [`get_access_token_cc`]
@@ -279,8 +566,14 @@ impl Open189App {
     /// is there; use one of [the `SmsCodeConfig` constructors][ctors] to pass the
     /// parameters.
     ///
+    /// If a rate limiter is installed with [`set_rate_limiter`], the send is
+    /// checked against it after validating the parameters. A send is counted
+    /// against the limits as soon as it passes the check, even if the request
+    /// then fails.
+    ///
     /// [`get_access_token_cc`]: #method.get_access_token_cc
     /// [`sms_get_token`]: #method.sms_get_token
+    /// [`set_rate_limiter`]: #method.set_rate_limiter
     /// [ctors]: enum.SmsCodeConfig.html#methods
     ///
     /// # Examples
@@ -293,7 +586,7 @@ impl Open189App {
     /// let access_token = fetch_cached_access_token();
     /// let sms_token = client.sms_get_token(access_token)?;
     ///
-    /// let config = SmsCodeConfig::prepared("12345678901", "234567", Some(5));
+    /// let config = SmsCodeConfig::prepared("18912345678", "234567", Some(5))?;
     /// let result = client.sms_send_verification_code(access_token, &sms_token, config)?;
     /// # Ok(())
     /// # }
@@ -303,43 +596,504 @@ impl Open189App {
                                                      sms_token: S,
                                                      config: SmsCodeConfig)
                                                      -> Result<msg::SentSmsCode> {
-        let mut params = HashMap::new();
-        params.insert("token", sms_token.as_ref().to_string());
+        let (path, params) = req::sms_code_params(sms_token.as_ref(), config)?;
+        self.check_rate_limit(&params["phone"])?;
+        self.post_verification_code(access_token.as_ref(), path, params)
+    }
 
-        let url;
-        match config {
-            SmsCodeConfig::Prepared(config) => {
-                url = URL_SMS_SEND_WITH_CODE;
+    /// Send a verification code with SMS in one go.
+    ///
+    /// This takes care of the tokens involved: an access token is requested
+    /// with the Client Credentials flow and cached for later sends, in
+    /// memory unless another store is set with [`set_token_store`], and a
+    /// fresh SMS token is requested for every send. If the API rejects the
+    /// access token at either step, a new one is requested and both steps are
+    /// tried once more.
+    ///
+    /// The parameters are validated, and the send checked against the rate
+    /// limiter if any, once before any request is made, so a retried send
+    /// only counts once against the limits.
+    ///
+    /// The time spent in each step is reported alongside the result. Use the
+    /// lower-level methods, such as [`sms_send_verification_code`], if you'd
+    /// like to manage the tokens yourself.
+    ///
+    /// [`set_token_store`]: #method.set_token_store
+    /// [`sms_send_verification_code`]: #method.sms_send_verification_code
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use open189::SmsCodeConfig;
+    ///
+    /// # fn foo(client: &::open189::Open189App) -> ::open189::errors::Result<()> {
+    /// let config = SmsCodeConfig::prepared("18912345678", "234567", Some(5))?;
+    /// let result = client.send_verification_code(config)?;
+    /// println!("queued SMS {} in {:?}", result.sent.sms_id, result.timing.total);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn send_verification_code(&self,
+                                  config: SmsCodeConfig)
+                                  -> Result<msg::SentVerificationCode> {
+        let started = Instant::now();
 
-                let code = config.code;
-                if code.len() != 6 {
-                    return Err(ErrorKind::WrongSmsCodeLength(code.len(), 6).into());
-                }
-                if !code.chars().all(|ch| ch.is_digit(10)) {
-                    return Err(ErrorKind::NonDigitInSmsCode(code.to_string()).into());
-                }
-                params.insert("randcode", code.to_string());
+        let (_, params) = req::sms_code_params("", config.clone())?;
+        self.check_rate_limit(&params["phone"])?;
 
-                params.insert("phone", config.phone.to_string());
-                if let Some(expire_time) = config.expire_time {
-                    params.insert("exp_time", format!("{}", expire_time));
+        let mut access_token_time = None;
+        let mut access_token = match self.tokens.cached_token(self.clock())? {
+            Some(token) => token,
+            None => {
+                let step_started = Instant::now();
+                let token = self.tokens.refresh(self, None)?;
+                access_token_time = Some(step_started.elapsed());
+                token
+            }
+        };
+
+        let mut retried = false;
+        loop {
+            let step_started = Instant::now();
+            let result = self.sms_get_token(access_token.as_str())
+                .map(|sms_token| (sms_token, step_started.elapsed()))
+                .and_then(|(sms_token, sms_token_time)| {
+                    let step_started = Instant::now();
+                    let (path, params) = req::sms_code_params(sms_token.as_str(),
+                                                              config.clone())?;
+                    self.post_verification_code(access_token.as_str(), path, params)
+                        .map(|sent| (sent, sms_token_time, step_started.elapsed()))
+                });
+
+            match result {
+                Ok((sent, sms_token_time, send_time)) => {
+                    debug!("SMS {} sent in {} ms: access token {}, SMS token {} ms, send {} ms",
+                           sent.sms_id,
+                           util::duration_millis(started.elapsed()),
+                           access_token_time.map_or("cached".to_string(),
+                                                    |t| format!("{} ms", util::duration_millis(t))),
+                           util::duration_millis(sms_token_time),
+                           util::duration_millis(send_time));
+                    return Ok(msg::SentVerificationCode {
+                        sent: sent,
+                        timing: msg::SendTiming {
+                            access_token: access_token_time,
+                            sms_token: sms_token_time,
+                            send: send_time,
+                            total: started.elapsed(),
+                        },
+                    });
                 }
+                Err(ref e) if !retried &&
+                              e.api_error_code().map_or(false, |c| c.is_token_error()) => {}
+                Err(e) => return Err(e),
+            }
+
+            // the access token is rejected; get a new one and try again
+            info!("access token rejected, requesting a new one");
+            retried = true;
+            let step_started = Instant::now();
+            access_token = self.tokens.refresh(self, Some(access_token.as_str()))?;
+            let elapsed = step_started.elapsed();
+            access_token_time = Some(access_token_time.map_or(elapsed, |t| t + elapsed));
+        }
+    }
+
+    /// Checks a verification code send to the phone number against the rate
+    /// limiter, if any.
+    fn check_rate_limit(&self, phone: &str) -> Result<()> {
+        if let Some(ref rate_limiter) = self.rate_limiter {
+            if let Err(e) = rate_limiter.check(phone) {
+                warn!("verification code to {} rejected: {}",
+                      redact::phone(phone, self.client.mask_phone_numbers()),
+                      e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn post_verification_code(&self,
+                              access_token: &str,
+                              path: &str,
+                              params: HashMap<&'static str, String>)
+                              -> Result<msg::SentSmsCode> {
+        let url = self.endpoints.api_url(path)?;
+        let phone = redact::phone(&params["phone"], self.client.mask_phone_numbers())
+            .into_owned();
+        let sent = self.client.post_sync::<_, _, resp::SmsCodeResponse>(self.app_id(),
+                                                                        self.secret(),
+                                                                        access_token,
+                                                                        path,
+                                                                        url,
+                                                                        params)?;
+        info!("verification code to {} queued as SMS {}", phone, sent.sms_id);
+        Ok(sent)
+    }
+
+    /// Send a SMS with a pre-approved template.
+    ///
+    /// Templates are registered and approved on the `open.189.cn` console,
+    /// and are referred to by their template IDs. The template parameters are
+    /// given as name-value pairs; names must consist of ASCII alphanumerics and
+    /// underscores only, and values can't be empty. They are validated locally
+    /// before firing the actual request.
+    ///
+    /// An access token is required; you can get one with the
+    /// [`get_access_token_cc`] method. Unlike verification codes, no separate
+    /// SMS token is needed.
+    ///
+    /// [`get_access_token_cc`]: #method.get_access_token_cc
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::collections::HashMap;
+    ///
+    /// # fn fetch_cached_access_token() -> &'static str { "dummy" }
+    /// # fn foo(client: &::open189::Open189App) -> ::open189::errors::Result<()> {
+    /// let access_token = fetch_cached_access_token();
+    ///
+    /// let mut params = HashMap::new();
+    /// params.insert("name", "John");
+    /// params.insert("order_id", "12345");
+    /// let result = client.sms_send_template(access_token, "18912345678", "91000001", &params)?;
+    /// println!("queued SMS {}", result.sms_id);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn sms_send_template<S, I, K, V>(&self,
+                                         access_token: S,
+                                         phone: S,
+                                         template_id: S,
+                                         template_params: I)
+                                         -> Result<msg::SentTemplateSms>
+        where S: AsRef<str>,
+              I: IntoIterator<Item = (K, V)>,
+              K: AsRef<str>,
+              V: AsRef<str>
+    {
+        let params = req::template_sms_params(phone.as_ref(),
+                                              template_id.as_ref(),
+                                              template_params)?;
+        let path = req::PATH_SMS_SEND_TEMPLATE;
+        let url = self.endpoints.api_url(path)?;
+        let phone = redact::phone(&params["acceptor_tel"], self.client.mask_phone_numbers())
+            .into_owned();
+        let sent = self.client.post_sync::<_, _, resp::TemplateSmsResponse>(self.app_id(),
+                                                                            self.secret(),
+                                                                            access_token.as_ref(),
+                                                                            path,
+                                                                            url,
+                                                                            params)?;
+        info!("template {} SMS to {} queued as SMS {}",
+              template_id.as_ref(),
+              phone,
+              sent.sms_id);
+        Ok(sent)
+    }
+}
+
+
+impl Open189AppBuilder {
+    /// Start building a client instance given the `open.189.cn` app ID and
+    /// secret.
+    ///
+    /// Unless configured otherwise, a Hyper client with default parameters
+    /// is used, requests go to the production endpoints, and are timestamped
+    /// with the system clock.
+    pub fn new<S: AsRef<str>>(app_id: S, secret: S) -> Open189AppBuilder {
+        Open189AppBuilder {
+            app_id: app_id.as_ref().to_string(),
+            secret: secret.as_ref().to_string(),
+            transport: TransportSource::Default(transport::hyper_client),
+            timeouts: None,
+            endpoints: Default::default(),
+            oauth_base: None,
+            api_base: None,
+            user_agent: None,
+            retry_policy: Default::default(),
+            clock: Arc::new(SystemClock),
+            skew_correction: false,
+            on_request: None,
+            on_response: None,
+            middleware: Vec::new(),
+            mask_phone_numbers: true,
+            metrics: None,
+        }
+    }
+
+    /// Use the provided Hyper client, for example one set up with a proxy.
+    pub fn client(self, client: Client) -> Open189AppBuilder {
+        self.transport(client)
+    }
+}
+
+
+impl<T: Transport> Open189AppBuilder<T> {
+    /// Use the provided HTTP transport instead of a Hyper client.
+    ///
+    /// See the [`transport`] module for details.
+    ///
+    /// [`transport`]: transport/index.html
+    pub fn transport<U: Transport>(self, transport: U) -> Open189AppBuilder<U> {
+        Open189AppBuilder {
+            app_id: self.app_id,
+            secret: self.secret,
+            transport: TransportSource::Custom(transport),
+            timeouts: self.timeouts,
+            endpoints: self.endpoints,
+            oauth_base: self.oauth_base,
+            api_base: self.api_base,
+            user_agent: self.user_agent,
+            retry_policy: self.retry_policy,
+            clock: self.clock,
+            skew_correction: self.skew_correction,
+            on_request: self.on_request,
+            on_response: self.on_response,
+            middleware: self.middleware,
+            mask_phone_numbers: self.mask_phone_numbers,
+            metrics: self.metrics,
+        }
+    }
+
+    /// Set the connect, read and write timeouts.
+    ///
+    /// Timeouts only apply to the Hyper client created by the builder; it's
+    /// an error to combine them with a custom transport or client.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Open189AppBuilder<T> {
+        self.timeouts = Some(timeouts);
+        self
+    }
+
+    /// Send requests to the given endpoints.
+    pub fn endpoints(mut self, endpoints: Endpoints) -> Open189AppBuilder<T> {
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// Override the base URL of the OAuth endpoints.
+    pub fn oauth_base<S: AsRef<str>>(mut self, oauth_base: S) -> Open189AppBuilder<T> {
+        self.oauth_base = Some(oauth_base.as_ref().to_string());
+        self
+    }
+
+    /// Override the base URL of the API endpoints.
+    pub fn api_base<S: AsRef<str>>(mut self, api_base: S) -> Open189AppBuilder<T> {
+        self.api_base = Some(api_base.as_ref().to_string());
+        self
+    }
+
+    /// Set the `User-Agent` header sent with every request.
+    pub fn user_agent<S: AsRef<str>>(mut self, user_agent: S) -> Open189AppBuilder<T> {
+        self.user_agent = Some(user_agent.as_ref().to_string());
+        self
+    }
+
+    /// Set the policy for retrying failed requests.
+    ///
+    /// See [`Open189App::set_retry_policy`] for details.
+    ///
+    /// [`Open189App::set_retry_policy`]: struct.Open189App.html#method.set_retry_policy
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Open189AppBuilder<T> {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Set the clock requests are timestamped with.
+    ///
+    /// See the [`clock`] module for details.
+    ///
+    /// [`clock`]: clock/index.html
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Open189AppBuilder<T> {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Enable or disable correction for skew from the API server's clock.
+    ///
+    /// See [`Open189App::set_skew_correction`] for details.
+    ///
+    /// [`Open189App::set_skew_correction`]: struct.Open189App.html#method.set_skew_correction
+    pub fn skew_correction(mut self, enabled: bool) -> Open189AppBuilder<T> {
+        self.skew_correction = enabled;
+        self
+    }
+
+    /// Set a hook called with every request right before it's sent,
+    /// including retries.
+    ///
+    /// The hook is called after every middleware layer, regardless of the
+    /// order they're added in.
+    pub fn on_request<F>(mut self, f: F) -> Open189AppBuilder<T>
+        where F: Fn(&HttpRequest) + Send + Sync + 'static
+    {
+        self.on_request = Some(Arc::new(f));
+        self
+    }
+
+    /// Set a hook called with the outcome of every request, including
+    /// retries.
+    ///
+    /// The hook is called before every middleware layer, regardless of the
+    /// order they're added in. See [`Exchange`] for what's observed.
+    ///
+    /// [`Exchange`]: middleware/struct.Exchange.html
+    pub fn on_response<F>(mut self, f: F) -> Open189AppBuilder<T>
+        where F: Fn(&Exchange) + Send + Sync + 'static
+    {
+        self.on_response = Some(Arc::new(f));
+        self
+    }
+
+    /// Add a layer to the middleware chain.
+    ///
+    /// Layers are called in the order they're added on the way out. See the
+    /// [`middleware`] module for details.
+    ///
+    /// [`middleware`]: middleware/index.html
+    pub fn middleware<M: Middleware + 'static>(mut self, layer: M) -> Open189AppBuilder<T> {
+        self.middleware.push(Arc::new(layer));
+        self
+    }
+
+    /// Set whether phone numbers are masked in log messages.
+    ///
+    /// See [`Open189App::set_mask_phone_numbers`] for details.
+    ///
+    /// [`Open189App::set_mask_phone_numbers`]: struct.Open189App.html#method.set_mask_phone_numbers
+    pub fn mask_phone_numbers(mut self, mask: bool) -> Open189AppBuilder<T> {
+        self.mask_phone_numbers = mask;
+        self
+    }
+
+    /// Report the metrics of every API call to the recorder.
+    ///
+    /// See the [`metrics`] module for details.
+    ///
+    /// [`metrics`]: metrics/index.html
+    pub fn metrics_recorder<M: MetricsRecorder + 'static>(mut self,
+                                                          recorder: M)
+                                                          -> Open189AppBuilder<T> {
+        self.metrics = Some(Arc::new(recorder));
+        self
+    }
+
+    /// Validate the configuration and build the client instance.
+    pub fn build(self) -> Result<Open189App<T>> {
+        if self.app_id.is_empty() {
+            return Err(ErrorKind::InvalidConfig(ConfigError::EmptyAppId).into());
+        }
+        if self.secret.is_empty() {
+            return Err(ErrorKind::InvalidConfig(ConfigError::EmptySecret).into());
+        }
+
+        let mut endpoints = self.endpoints;
+        if let Some(ref oauth_base) = self.oauth_base {
+            if endpoints.set_oauth_base(oauth_base.as_str()).is_err() {
+                let e = ConfigError::InvalidBaseUrl(oauth_base.clone());
+                return Err(ErrorKind::InvalidConfig(e).into());
+            }
+        }
+        if let Some(ref api_base) = self.api_base {
+            if endpoints.set_api_base(api_base.as_str()).is_err() {
+                let e = ConfigError::InvalidBaseUrl(api_base.clone());
+                return Err(ErrorKind::InvalidConfig(e).into());
+            }
+        }
+
+        if let Some(ref user_agent) = self.user_agent {
+            let valid = !user_agent.trim().is_empty() &&
+                        user_agent.bytes().all(|b| b >= 0x20 && b < 0x7f);
+            if !valid {
+                let e = ConfigError::InvalidUserAgent(user_agent.clone());
+                return Err(ErrorKind::InvalidConfig(e).into());
             }
-            SmsCodeConfig::Callback(config) => {
-                url = URL_SMS_SEND_WITH_CALLBACK;
+        }
+
+        if self.retry_policy.max_attempts() == 0 {
+            return Err(ErrorKind::InvalidConfig(ConfigError::ZeroMaxAttempts).into());
+        }
 
-                params.insert("phone", config.phone.to_string());
-                params.insert("url", config.url.into_string());
-                if let Some(expire_time) = config.expire_time {
-                    params.insert("exp_time", format!("{}", expire_time));
+        let transport = match self.transport {
+            TransportSource::Custom(_) if self.timeouts.is_some() => {
+                return Err(ErrorKind::InvalidConfig(ConfigError::TimeoutsWithCustomTransport)
+                    .into());
+            }
+            TransportSource::Custom(transport) => transport,
+            TransportSource::Default(make) => make(&self.timeouts.unwrap_or_default()),
+        };
+
+        let mut app = Open189App::with_transport(self.app_id, self.secret, transport);
+        app.set_endpoints(endpoints);
+        app.client.set_retry_policy(self.retry_policy);
+        app.client.set_clock(self.clock);
+        app.client.set_skew_correction(self.skew_correction);
+        app.client.set_user_agent(self.user_agent);
+        app.client.set_middleware(self.middleware);
+        if self.on_request.is_some() || self.on_response.is_some() {
+            app.client.set_hooks(Some(Arc::new(Hooks {
+                on_request: self.on_request,
+                on_response: self.on_response,
+            })));
+        }
+        app.client.set_mask_phone_numbers(self.mask_phone_numbers);
+        if let Some(metrics) = self.metrics {
+            app.client.set_metrics_recorder(metrics);
+        }
+        Ok(app)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn config_error<T: Transport>(builder: Open189AppBuilder<T>) -> ConfigError {
+        match builder.build() {
+            Ok(_) => panic!("configuration unexpectedly valid"),
+            Err(e) => {
+                match *e.kind() {
+                    ErrorKind::InvalidConfig(ref e) => e.clone(),
+                    ref e => panic!("unexpected error {:?}", e),
                 }
             }
         }
+    }
 
-        self.client.post_sync::<_, _, resp::SmsCodeResponse>(self.app_id(),
-                                                             self.secret(),
-                                                             access_token.as_ref(),
-                                                             url,
-                                                             params)
+
+    #[test]
+    fn test_builder_validation() {
+        assert_eq!(config_error(Open189AppBuilder::new("", "secret")),
+                   ConfigError::EmptyAppId);
+        assert_eq!(config_error(Open189AppBuilder::new("123", "")),
+                   ConfigError::EmptySecret);
+        assert_eq!(config_error(Open189AppBuilder::new("123", "secret").api_base("not a url")),
+                   ConfigError::InvalidBaseUrl("not a url".to_string()));
+        assert_eq!(config_error(Open189AppBuilder::new("123", "secret").user_agent("a\nb")),
+                   ConfigError::InvalidUserAgent("a\nb".to_string()));
+        assert_eq!(config_error(Open189AppBuilder::new("123", "secret")
+                       .retry_policy(RetryPolicy::new(0))),
+                   ConfigError::ZeroMaxAttempts);
+        assert_eq!(config_error(Open189AppBuilder::new("123", "secret")
+                       .timeouts(Default::default())
+                       .client(Client::new())),
+                   ConfigError::TimeoutsWithCustomTransport);
+    }
+
+
+    #[test]
+    fn test_builder() {
+        let app = Open189AppBuilder::new("123", "secret")
+            .api_base("http://127.0.0.1:8080/api")
+            .timeouts(Default::default())
+            .user_agent("test/1.0")
+            .build()
+            .unwrap();
+        assert_eq!(app.app_id(), "123");
+        assert_eq!(app.endpoints().api_base().as_str(), "http://127.0.0.1:8080/api/");
+        assert_eq!(app.endpoints().oauth_base().as_str(), "https://oauth.api.189.cn/");
     }
 }
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::{future, BoxFuture, Future};
+use futures_cpupool::CpuPool;
+
+use hyper::client::Client;
+
+use super::clock::Clock;
+use super::endpoints::Endpoints;
+use super::errors::*;
+use super::metrics::MetricsRecorder;
+use super::middleware::Middleware;
+use super::msg;
+use super::net::Open189Client;
+use super::ratelimit::RateLimiter;
+use super::redact;
+use super::req::{self, SmsCodeConfig};
+use super::resp;
+use super::retry::RetryPolicy;
+use super::transport::{AsyncTransport, CpuPoolTransport};
+
+
+/// Asynchronous client for the `open.189.cn` API.
+///
+/// This is the `futures`-based counterpart of [`Open189App`]; the methods
+/// mirror their blocking versions, except that futures are returned instead.
+/// Requests go through the same pipeline as with the blocking client: the
+/// retry policy, skew correction, middleware, metrics, logging and rate
+/// limiting are configured with the same methods and behave the same, except
+/// for the differences below.
+///
+/// * The delays between retries are waited for by the transport, see
+///   [`AsyncTransport::delay`].
+/// * Deadlines set with [`Open189App::with_deadline`] don't apply.
+/// * There's no builder: the user agent and the `on_request` and
+///   `on_response` hooks can't be set, but [`add_middleware`] does the job
+///   of the hooks.
+/// * Only the methods below are provided; there's no Authorization Code
+///   flow, template SMS or one-shot [`Open189App::send_verification_code`].
+///
+/// The I/O is only as asynchronous as the transport. The default transport is
+/// a [`CpuPoolTransport`]: the blocking Hyper client run on a thread pool,
+/// with one thread per CPU. Each request in flight occupies a pool thread
+/// for its whole duration, blocking it on the network, so no more requests
+/// than there are threads are in flight at once; the others wait for a
+/// thread to free up. Size the pool after the concurrency you need, or
+/// provide a truly non-blocking [`AsyncTransport`].
+///
+/// [`Open189App`]: struct.Open189App.html
+/// [`Open189App::with_deadline`]: struct.Open189App.html#method.with_deadline
+/// [`Open189App::send_verification_code`]: struct.Open189App.html#method.send_verification_code
+/// [`AsyncTransport::delay`]: transport/trait.AsyncTransport.html#method.delay
+/// [`add_middleware`]: #method.add_middleware
+/// [`CpuPoolTransport`]: transport/struct.CpuPoolTransport.html
+/// [`AsyncTransport`]: transport/trait.AsyncTransport.html
+pub struct AsyncOpen189App<T: AsyncTransport = CpuPoolTransport<Client>> {
+    app_id: String,
+    secret: String,
+    client: Arc<Open189Client<T>>,
+    endpoints: Endpoints,
+    rate_limiter: Option<RateLimiter>,
+}
+
+
+impl AsyncOpen189App {
+    /// Construct a client instance given the `open.189.cn` app ID and secret.
+    ///
+    /// A Hyper client is created with default parameters, and driven on a
+    /// thread pool with one thread per CPU, which thus bounds the number of
+    /// requests in flight. Use [`with_transport`] to provide your own
+    /// transport instead, e.g. with a larger pool.
+    ///
+    /// [`with_transport`]: #method.with_transport
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use open189::AsyncOpen189App;
+    ///
+    /// let app_id = "your app id here";
+    /// let secret = "your app secret here";
+    /// let client = AsyncOpen189App::new(app_id, secret);
+    /// ```
+    pub fn new<S: AsRef<str>>(app_id: S, secret: S) -> AsyncOpen189App {
+        let transport = CpuPoolTransport::new(Client::new(), CpuPool::new_num_cpus());
+        AsyncOpen189App::with_transport(app_id, secret, transport)
+    }
+}
+
+
+impl<T: AsyncTransport> AsyncOpen189App<T> {
+    /// Construct a client instance with the provided asynchronous transport.
+    pub fn with_transport<S: AsRef<str>>(app_id: S,
+                                         secret: S,
+                                         transport: T)
+                                         -> AsyncOpen189App<T> {
+        AsyncOpen189App {
+            app_id: app_id.as_ref().to_string(),
+            secret: secret.as_ref().to_string(),
+            client: Arc::new(Open189Client::new(transport)),
+            endpoints: Default::default(),
+            rate_limiter: None,
+        }
+    }
+
+    /// Get the app ID the client is created with.
+    pub fn app_id(&self) -> &str {
+        &self.app_id
+    }
+
+    /// Get the app secret the client is created with.
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    /// Get the endpoints requests are sent to.
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    /// Set the endpoints requests are sent to.
+    ///
+    /// The production endpoints are used by default.
+    pub fn set_endpoints(&mut self, endpoints: Endpoints) {
+        self.endpoints = endpoints;
+    }
+
+    /// Get the policy for retrying failed requests.
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        self.client.retry_policy()
+    }
+
+    /// Set the policy for retrying failed requests.
+    ///
+    /// See [`Open189App::set_retry_policy`] for details. Like the other
+    /// settings, this only affects calls started afterwards.
+    ///
+    /// [`Open189App::set_retry_policy`]: struct.Open189App.html#method.set_retry_policy
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.client_mut().set_retry_policy(retry_policy);
+    }
+
+    /// Set the clock requests are timestamped with.
+    ///
+    /// See [`Open189App::set_clock`] for details.
+    ///
+    /// [`Open189App::set_clock`]: struct.Open189App.html#method.set_clock
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.client_mut().set_clock(Arc::new(clock));
+    }
+
+    /// Enable or disable correction for skew from the API server's clock.
+    ///
+    /// See [`Open189App::set_skew_correction`] for details.
+    ///
+    /// [`Open189App::set_skew_correction`]: struct.Open189App.html#method.set_skew_correction
+    pub fn set_skew_correction(&mut self, enabled: bool) {
+        self.client_mut().set_skew_correction(enabled);
+    }
+
+    /// Add a layer to the end of the middleware chain.
+    ///
+    /// See the [`middleware`] module for details.
+    ///
+    /// [`middleware`]: middleware/index.html
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, layer: M) {
+        self.client_mut().push_middleware(Arc::new(layer));
+    }
+
+    /// Set whether phone numbers are masked in log messages.
+    ///
+    /// See [`Open189App::set_mask_phone_numbers`] for details.
+    ///
+    /// [`Open189App::set_mask_phone_numbers`]: struct.Open189App.html#method.set_mask_phone_numbers
+    pub fn set_mask_phone_numbers(&mut self, mask: bool) {
+        self.client_mut().set_mask_phone_numbers(mask);
+    }
+
+    /// Report the metrics of every API call to the recorder.
+    ///
+    /// See the [`metrics`] module for details.
+    ///
+    /// [`metrics`]: metrics/index.html
+    pub fn set_metrics_recorder<M: MetricsRecorder + 'static>(&mut self, recorder: M) {
+        self.client_mut().set_metrics_recorder(Arc::new(recorder));
+    }
+
+    /// Get the rate limiter in front of SMS sending, if any.
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    /// Install or remove the rate limiter in front of SMS sending.
+    ///
+    /// See [`Open189App::set_rate_limiter`] for details.
+    ///
+    /// [`Open189App::set_rate_limiter`]: struct.Open189App.html#method.set_rate_limiter
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<RateLimiter>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    /// Request a user-independent access token with the Client Credentials flow.
+    ///
+    /// See [`Open189App::get_access_token_cc`] for details.
+    ///
+    /// [`Open189App::get_access_token_cc`]: struct.Open189App.html#method.get_access_token_cc
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # extern crate futures;
+    /// # extern crate open189;
+    /// use futures::Future;
+    ///
+    /// # fn main() {
+    /// let client = open189::AsyncOpen189App::new("your app id here", "your app secret here");
+    /// let access_token = client.get_access_token_cc().wait();
+    /// # }
+    /// ```
+    pub fn get_access_token_cc(&self) -> BoxFuture<msg::AccessToken, Error> {
+        let path = req::PATH_ACCESS_TOKEN;
+        let url = match self.endpoints.oauth_url(path) {
+            Ok(url) => url,
+            Err(e) => return future::err(e).boxed(),
+        };
+        let params = req::access_token_cc_params();
+        Open189Client::access_token_req_async::<_, _, resp::AccessTokenResponse>(&self.client,
+                                                                                 self.app_id(),
+                                                                                 self.secret(),
+                                                                                 path,
+                                                                                 url,
+                                                                                 params)
+    }
+
+    /// Request a token for use in the SMS sending API.
+    ///
+    /// See [`Open189App::sms_get_token`] for details.
+    ///
+    /// [`Open189App::sms_get_token`]: struct.Open189App.html#method.sms_get_token
+    pub fn sms_get_token<S: AsRef<str>>(&self, access_token: S) -> BoxFuture<String, Error> {
+        let path = req::PATH_SMS_TOKEN;
+        let url = match self.endpoints.api_url(path) {
+            Ok(url) => url,
+            Err(e) => return future::err(e).boxed(),
+        };
+        let params = HashMap::new();
+        Open189Client::get_async::<_, _, resp::SmsTokenResponse>(&self.client,
+                                                                 self.app_id(),
+                                                                 self.secret(),
+                                                                 access_token.as_ref(),
+                                                                 path,
+                                                                 url,
+                                                                 params)
+    }
+
+    /// Send a verification code with SMS.
+    ///
+    /// See [`Open189App::sms_send_verification_code`] for details. Validation
+    /// and rate limiting errors are reported through the returned future as
+    /// well.
+    ///
+    /// [`Open189App::sms_send_verification_code`]: struct.Open189App.html#method.sms_send_verification_code
+    pub fn sms_send_verification_code<S: AsRef<str>>(&self,
+                                                     access_token: S,
+                                                     sms_token: S,
+                                                     config: SmsCodeConfig)
+                                                     -> BoxFuture<msg::SentSmsCode, Error> {
+        let (path, params) = match req::sms_code_params(sms_token.as_ref(), config) {
+            Ok(prepared) => prepared,
+            Err(e) => return future::err(e).boxed(),
+        };
+        if let Err(e) = self.check_rate_limit(&params["phone"]) {
+            return future::err(e).boxed();
+        }
+        let url = match self.endpoints.api_url(path) {
+            Ok(url) => url,
+            Err(e) => return future::err(e).boxed(),
+        };
+        let phone = redact::phone(&params["phone"], self.client.mask_phone_numbers())
+            .into_owned();
+        Open189Client::post_async::<_, _, resp::SmsCodeResponse>(&self.client,
+                                                                 self.app_id(),
+                                                                 self.secret(),
+                                                                 access_token.as_ref(),
+                                                                 path,
+                                                                 url,
+                                                                 params)
+            .map(move |sent| {
+                info!("verification code to {} queued as SMS {}", phone, sent.sms_id);
+                sent
+            })
+            .boxed()
+    }
+
+    /// Gets the client to configure. Calls in flight keep using the settings
+    /// they were started with, so the client is copied if there are any.
+    fn client_mut(&mut self) -> &mut Open189Client<T> {
+        Arc::make_mut(&mut self.client)
+    }
+
+    fn check_rate_limit(&self, phone: &str) -> Result<()> {
+        if let Some(ref rate_limiter) = self.rate_limiter {
+            if let Err(e) = rate_limiter.check(phone) {
+                warn!("verification code to {} rejected: {}",
+                      redact::phone(phone, self.client.mask_phone_numbers()),
+                      e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,355 @@
+//! Command-line tool for the `open.189.cn` API.
+//!
+//! Credentials are read from the `OPEN189_APP_ID` and `OPEN189_SECRET`
+//! environment variables, falling back to a config file of `key = value`
+//! lines. The access token is cached in a file between runs.
+
+extern crate hyper;
+extern crate open189;
+extern crate serde_json;
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::UNIX_EPOCH;
+
+use hyper::client::Client;
+
+use open189::{Open189App, Open189AppBuilder, SmsCodeConfig};
+use open189::callback;
+use open189::errors::*;
+use open189::token::{FileTokenStore, TokenManager, TokenStore};
+
+const USAGE: &'static str = "\
+usage: open189 [--config <path>] [--json] <command> [<args>]
+
+commands:
+    token [--refresh]
+        print the cached access token, requesting a new one if necessary
+    sms-token
+        request a token for sending one SMS
+    send-code <phone> <code> [--expire <minutes>]
+        send a locally generated verification code
+    send-code <phone> --callback <url> [--expire <minutes>]
+        have the API generate a code and POST it to the callback URL
+    verify-sign [<body>]
+        verify and parse a callback request body, read from stdin if omitted
+
+environment:
+    OPEN189_APP_ID, OPEN189_SECRET    app credentials
+    OPEN189_OAUTH_BASE, OPEN189_API_BASE
+                                      base URLs of the API
+    OPEN189_TOKEN_CACHE               file the access token is cached in
+    OPEN189_CONFIG                    config file, defaults to
+                                      ~/.config/open189/config
+
+The config file holds `key = value` lines, with the keys app_id, secret,
+oauth_base, api_base and token_cache; environment variables take precedence.
+";
+
+
+/// Settings from the environment and the config file.
+struct Config {
+    values: HashMap<String, String>,
+}
+
+
+impl Config {
+    fn load(path: Option<&str>) -> Result<Config> {
+        let path = match path {
+            Some(path) => Some(PathBuf::from(path)),
+            None => env::var_os("OPEN189_CONFIG").map(PathBuf::from).or_else(|| {
+                home_dir().map(|home| home.join(".config/open189/config"))
+            }),
+        };
+
+        let mut values = HashMap::new();
+        if let Some(path) = path {
+            match File::open(&path) {
+                Ok(mut file) => {
+                    let mut content = String::new();
+                    file.read_to_string(&mut content)?;
+                    values = parse_config(&content)?;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(Config { values: values })
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        env::var(format!("OPEN189_{}", key.to_uppercase()))
+            .ok()
+            .or_else(|| self.values.get(key).cloned())
+    }
+
+    fn require(&self, key: &str) -> Result<String> {
+        match self.get(key) {
+            Some(value) => Ok(value),
+            None => {
+                Err(format!("{} not configured; set OPEN189_{} or add it to the config file",
+                            key,
+                            key.to_uppercase())
+                    .into())
+            }
+        }
+    }
+
+    fn token_cache(&self) -> Result<PathBuf> {
+        match self.get("token_cache") {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => {
+                match home_dir() {
+                    Some(home) => Ok(home.join(".cache/open189/token.json")),
+                    None => Err("can't determine token cache path; set OPEN189_TOKEN_CACHE".into()),
+                }
+            }
+        }
+    }
+}
+
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME").map(PathBuf::from)
+}
+
+
+fn parse_config(content: &str) -> Result<HashMap<String, String>> {
+    let mut values = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => {
+                values.insert(key.trim().to_string(), value.trim().to_string());
+            }
+            _ => return Err(format!("config file line {}: expected `key = value`", i + 1).into()),
+        }
+    }
+    Ok(values)
+}
+
+
+/// Command-line arguments left after the global options.
+struct Args {
+    args: Vec<String>,
+}
+
+
+impl Args {
+    /// Removes the flag, returning whether it was present.
+    fn flag(&mut self, name: &str) -> bool {
+        match self.args.iter().position(|arg| arg == name) {
+            Some(i) => {
+                self.args.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the option and its value, returning the value if present.
+    fn option(&mut self, name: &str) -> Result<Option<String>> {
+        match self.args.iter().position(|arg| arg == name) {
+            Some(i) if i + 1 < self.args.len() => {
+                self.args.remove(i);
+                Ok(Some(self.args.remove(i)))
+            }
+            Some(_) => Err(format!("missing value for {}", name).into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the remaining positional arguments, rejecting unknown options.
+    fn positional(self, min: usize, max: usize) -> Result<Vec<String>> {
+        if let Some(arg) = self.args.iter().find(|arg| arg.starts_with("--")) {
+            return Err(format!("unknown option {}", arg).into());
+        }
+        if self.args.len() < min || self.args.len() > max {
+            return Err("wrong number of arguments".into());
+        }
+        Ok(self.args)
+    }
+}
+
+
+/// Prints the fields either as `key: value` lines or as a JSON object.
+fn output(json: bool, fields: Vec<(&'static str, String)>) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if json {
+        let object: BTreeMap<_, _> = fields.into_iter().collect();
+        writeln!(stdout, "{}", serde_json::to_string(&object)?)?;
+    } else {
+        for (key, value) in fields {
+            writeln!(stdout, "{}: {}", key, value)?;
+        }
+    }
+    Ok(())
+}
+
+
+fn build_app(config: &Config) -> Result<Open189App> {
+    let mut builder = Open189AppBuilder::new(config.require("app_id")?,
+                                             config.require("secret")?)
+        .user_agent(concat!("open189-cli/", env!("CARGO_PKG_VERSION")));
+    if let Some(oauth_base) = config.get("oauth_base") {
+        builder = builder.oauth_base(oauth_base);
+    }
+    if let Some(api_base) = config.get("api_base") {
+        builder = builder.api_base(api_base);
+    }
+    builder.build()
+}
+
+
+fn token_store(config: &Config) -> Result<FileTokenStore> {
+    let path = config.token_cache()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(FileTokenStore::new(&path))
+}
+
+
+fn token_manager(config: &Config) -> Result<TokenManager<Client, FileTokenStore>> {
+    Ok(TokenManager::with_store(build_app(config)?, token_store(config)?))
+}
+
+
+fn cmd_token(config: &Config, json: bool, mut args: Args) -> Result<()> {
+    let refresh = args.flag("--refresh");
+    args.positional(0, 0)?;
+
+    let manager = token_manager(config)?;
+    if refresh {
+        manager.invalidate()?;
+    }
+    let token = manager.access_token()?;
+
+    let mut fields = vec![("access_token", token)];
+    if let Some(stored) = manager.store().load()? {
+        let issued_at = stored.issued_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        fields.push(("issued_at", issued_at.as_secs().to_string()));
+        fields.push(("expires_in", stored.token.expires_in.to_string()));
+    }
+    output(json, fields)
+}
+
+
+fn cmd_sms_token(config: &Config, json: bool, args: Args) -> Result<()> {
+    args.positional(0, 0)?;
+
+    let manager = token_manager(config)?;
+    let sms_token = manager.with_access_token(|access_token| {
+        manager.app().sms_get_token(access_token)
+    })?;
+    output(json, vec![("sms_token", sms_token)])
+}
+
+
+fn cmd_send_code(config: &Config, json: bool, mut args: Args) -> Result<()> {
+    let callback_url = args.option("--callback")?;
+    let expire_time = match args.option("--expire")? {
+        Some(minutes) => {
+            match minutes.parse() {
+                Ok(minutes) => Some(minutes),
+                Err(_) => return Err(format!("invalid expiry time {:?}", minutes).into()),
+            }
+        }
+        None => None,
+    };
+    let positional = if callback_url.is_some() {
+        args.positional(1, 1)?
+    } else {
+        args.positional(2, 2)?
+    };
+    let phone = positional[0].as_str();
+
+    let sms_config = match callback_url {
+        Some(ref url) => SmsCodeConfig::callback(phone, url.as_str(), expire_time)?,
+        None => SmsCodeConfig::prepared(phone, positional[1].as_str(), expire_time)?,
+    };
+    let mut app = build_app(config)?;
+    app.set_token_store(token_store(config)?);
+    let sent = app.send_verification_code(sms_config)?.sent;
+
+    output(json,
+           vec![("sms_id", sent.sms_id), ("send_time", sent.send_time.to_string())])
+}
+
+
+fn cmd_verify_sign(config: &Config, json: bool, args: Args) -> Result<()> {
+    let positional = args.positional(0, 1)?;
+    let body = match positional.into_iter().next() {
+        Some(body) => body.into_bytes(),
+        None => {
+            let mut body = Vec::new();
+            io::stdin().read_to_end(&mut body)?;
+            body
+        }
+    };
+
+    let code = callback::parse_callback(trim_newline(&body), config.require("secret")?)?;
+    output(json,
+           vec![("phone", code.phone),
+                ("rand_code", code.rand_code),
+                ("identifier", code.identifier)])
+}
+
+
+fn trim_newline(body: &[u8]) -> &[u8] {
+    let mut end = body.len();
+    while end > 0 && (body[end - 1] == b'\n' || body[end - 1] == b'\r') {
+        end -= 1;
+    }
+    &body[..end]
+}
+
+
+fn run(args: Vec<String>) -> Result<()> {
+    let mut args = Args { args: args };
+    if args.flag("--help") || args.flag("-h") {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    let json = args.flag("--json");
+    let config_path = args.option("--config")?;
+    let config = Config::load(config_path.as_ref().map(|p| p.as_str()))?;
+
+    if args.args.is_empty() {
+        return Err("missing command".into());
+    }
+    let command = args.args.remove(0);
+    match command.as_str() {
+        "token" => cmd_token(&config, json, args),
+        "sms-token" => cmd_sms_token(&config, json, args),
+        "send-code" => cmd_send_code(&config, json, args),
+        "verify-sign" => cmd_verify_sign(&config, json, args),
+        _ => Err(format!("unknown command {:?}", command).into()),
+    }
+}
+
+
+fn main() {
+    let args: Vec<_> = env::args().skip(1).collect();
+    if let Err(e) = run(args) {
+        let _ = writeln!(io::stderr(), "open189: {}", e);
+        if let ErrorKind::Msg(_) = *e.kind() {
+            let _ = writeln!(io::stderr(), "run `open189 --help` for usage");
+        }
+        process::exit(1);
+    }
+}
+
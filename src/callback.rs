@@ -0,0 +1,165 @@
+//! Receiving API-generated verification codes.
+//!
+//! When sending verification codes configured with [`SmsCodeConfig::callback`],
+//! the API generates the code itself and `POST`s it to the callback URL
+//! provided, signed with your app secret. Use [`parse_callback`] to verify and
+//! parse such a request in whatever web framework you're using, or mount a
+//! [`CallbackHandler`] on a Hyper server if you don't have one.
+//!
+//! [`SmsCodeConfig::callback`]: ../enum.SmsCodeConfig.html#method.callback
+//! [`parse_callback`]: fn.parse_callback.html
+//! [`CallbackHandler`]: struct.CallbackHandler.html
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use hyper::method::Method;
+use hyper::server::{Handler, Request, Response};
+use hyper::status::StatusCode;
+
+use url::form_urlencoded;
+
+use super::errors::*;
+use super::msg;
+use super::signature;
+
+const CALLBACK_RESPONSE: &'static [u8] = b"{\"res_code\":0}";
+
+
+fn take_param(params: &mut HashMap<String, String>, name: &str) -> Result<String> {
+    match params.remove(name) {
+        Some(value) => Ok(value),
+        None => Err(ErrorKind::MissingCallbackParam(name.to_string()).into()),
+    }
+}
+
+
+/// Verify and parse the `application/x-www-form-urlencoded` body of a
+/// verification code callback request.
+///
+/// The `sign` parameter of the request is checked against the signature of
+/// the other parameters, computed with the app secret; an `Err` is returned
+/// if it doesn't match.
+///
+/// # Examples
+///
+/// ```no_run
+/// use open189::callback::parse_callback;
+///
+/// # fn foo(body: &[u8]) -> open189::errors::Result<()> {
+/// let code = parse_callback(body, "your app secret here")?;
+/// println!("code for {} is {}", code.phone, code.rand_code);
+/// # Ok(())
+/// # }
+/// ```
+pub fn parse_callback<S: AsRef<str>>(body: &[u8], secret: S) -> Result<msg::ReceivedSmsCode> {
+    let mut params: HashMap<String, String> = form_urlencoded::parse(body).into_owned().collect();
+
+    let sign = take_param(&mut params, "sign")?;
+    if !signature::verify(&params, secret, sign) {
+        return Err(ErrorKind::InvalidSignature.into());
+    }
+
+    Ok(msg::ReceivedSmsCode {
+        phone: take_param(&mut params, "phone")?,
+        rand_code: take_param(&mut params, "rand_code")?,
+        identifier: take_param(&mut params, "identifier")?,
+    })
+}
+
+
+/// A tiny Hyper request handler for verification code callbacks.
+///
+/// Verified codes are handed to the closure provided; requests failing
+/// verification are rejected with `400 Bad Request`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate hyper;
+/// # extern crate open189;
+/// use open189::callback::CallbackHandler;
+///
+/// # fn main() {
+/// let handler = CallbackHandler::new("your app secret here", |code| {
+///     println!("code for {} is {}", code.phone, code.rand_code);
+/// });
+/// hyper::server::Server::http("0.0.0.0:8080").unwrap().handle(handler).unwrap();
+/// # }
+/// ```
+pub struct CallbackHandler<F> {
+    secret: String,
+    f: F,
+}
+
+
+impl<F> CallbackHandler<F>
+    where F: Fn(msg::ReceivedSmsCode) + Send + Sync
+{
+    /// Construct a handler verifying requests with the given app secret.
+    pub fn new<S: AsRef<str>>(secret: S, f: F) -> CallbackHandler<F> {
+        CallbackHandler {
+            secret: secret.as_ref().to_string(),
+            f: f,
+        }
+    }
+}
+
+
+impl<F> Handler for CallbackHandler<F>
+    where F: Fn(msg::ReceivedSmsCode) + Send + Sync
+{
+    fn handle(&self, mut req: Request, mut res: Response) {
+        if req.method != Method::Post {
+            *res.status_mut() = StatusCode::MethodNotAllowed;
+            return;
+        }
+
+        let mut body = Vec::new();
+        if req.read_to_end(&mut body).is_err() {
+            *res.status_mut() = StatusCode::BadRequest;
+            return;
+        }
+
+        match parse_callback(&body, &self.secret) {
+            Ok(code) => {
+                (self.f)(code);
+                let _ = res.send(CALLBACK_RESPONSE);
+            }
+            Err(_) => *res.status_mut() = StatusCode::BadRequest,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_parse_callback() {
+        let mut params = HashMap::new();
+        params.insert("identifier", "abc");
+        params.insert("phone", "12345678901");
+        params.insert("rand_code", "123456");
+        let sign = signature::sign(&params, "012345");
+
+        let body = {
+            let mut serializer = form_urlencoded::Serializer::new(String::new());
+            for (k, v) in &params {
+                serializer.append_pair(k, v);
+            }
+            serializer.append_pair("sign", &sign);
+            serializer.finish()
+        };
+
+        let code = parse_callback(body.as_bytes(), "012345").unwrap();
+        assert_eq!(code.phone, "12345678901");
+        assert_eq!(code.rand_code, "123456");
+        assert_eq!(code.identifier, "abc");
+
+        assert!(parse_callback(body.as_bytes(), "543210").is_err());
+        assert!(parse_callback(b"phone=12345678901", "012345").is_err());
+    }
+}
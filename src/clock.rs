@@ -0,0 +1,84 @@
+//! Sources of the current time.
+//!
+//! Every request to the API is timestamped, and the API rejects requests
+//! whose timestamps are too far off. The time is taken from the [`Clock`]
+//! configured with [`Open189AppBuilder::clock`], which defaults to the system
+//! clock; tests can pin the time with a [`FixedClock`].
+//!
+//! If the local clock drifts, enable skew correction with
+//! [`Open189AppBuilder::skew_correction`]: the offset from the API server's
+//! clock is then measured from the `Date` header of responses, and applied
+//! to subsequent timestamps.
+//!
+//! [`Clock`]: trait.Clock.html
+//! [`FixedClock`]: struct.FixedClock.html
+//! [`Open189AppBuilder::clock`]: ../struct.Open189AppBuilder.html#method.clock
+//! [`Open189AppBuilder::skew_correction`]: ../struct.Open189AppBuilder.html#method.skew_correction
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+
+/// A source of the current time.
+///
+/// Implementations must be safe to share between threads.
+pub trait Clock: Send + Sync {
+    /// Get the current time.
+    fn now(&self) -> SystemTime;
+}
+
+
+/// The system clock.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct SystemClock;
+
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+
+/// A clock standing still at a settable time, for testing.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// use open189::clock::{Clock, FixedClock};
+///
+/// let clock = FixedClock::new(UNIX_EPOCH);
+/// clock.advance(Duration::from_secs(60));
+/// assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(60));
+/// ```
+#[derive(Debug)]
+pub struct FixedClock {
+    now: Mutex<SystemTime>,
+}
+
+
+impl FixedClock {
+    /// Construct a clock showing the given time.
+    pub fn new(now: SystemTime) -> FixedClock {
+        FixedClock { now: Mutex::new(now) }
+    }
+
+    /// Set the time shown.
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    /// Move the time shown forward.
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
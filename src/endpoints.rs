@@ -0,0 +1,125 @@
+use hyper::client::IntoUrl;
+
+use url::Url;
+
+use super::errors::*;
+
+const DEFAULT_OAUTH_BASE: &'static str = "https://oauth.api.189.cn/";
+const DEFAULT_API_BASE: &'static str = "http://api.189.cn/";
+
+
+/// Base URLs of the `open.189.cn` API.
+///
+/// Requests go to the production hosts by default. Point them elsewhere, for
+/// example to a staging environment or a local stand-in, by overriding the
+/// OAuth base, the API base, or both. Endpoint paths are resolved relative to
+/// the bases, so a base may include a path prefix.
+///
+/// # Examples
+///
+/// ```
+/// use open189::Endpoints;
+///
+/// let mut endpoints = Endpoints::default();
+/// endpoints.set_api_base("http://localhost:8080/open189").unwrap();
+///
+/// assert_eq!(endpoints.api_url("v2/dm/randcode/token").unwrap().as_str(),
+///            "http://localhost:8080/open189/v2/dm/randcode/token");
+/// ```
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Endpoints {
+    oauth_base: Url,
+    api_base: Url,
+}
+
+
+impl Endpoints {
+    /// Construct endpoints with the given OAuth and API base URLs.
+    pub fn new<U: IntoUrl, V: IntoUrl>(oauth_base: U, api_base: V) -> Result<Endpoints> {
+        Ok(Endpoints {
+            oauth_base: normalize_base(oauth_base)?,
+            api_base: normalize_base(api_base)?,
+        })
+    }
+
+    /// Get the base URL of the OAuth endpoints.
+    pub fn oauth_base(&self) -> &Url {
+        &self.oauth_base
+    }
+
+    /// Get the base URL of the API endpoints.
+    pub fn api_base(&self) -> &Url {
+        &self.api_base
+    }
+
+    /// Override the base URL of the OAuth endpoints.
+    pub fn set_oauth_base<U: IntoUrl>(&mut self, oauth_base: U) -> Result<()> {
+        self.oauth_base = normalize_base(oauth_base)?;
+        Ok(())
+    }
+
+    /// Override the base URL of the API endpoints.
+    pub fn set_api_base<U: IntoUrl>(&mut self, api_base: U) -> Result<()> {
+        self.api_base = normalize_base(api_base)?;
+        Ok(())
+    }
+
+    /// Resolve the path of an OAuth endpoint against the OAuth base.
+    pub fn oauth_url(&self, path: &str) -> Result<Url> {
+        Ok(self.oauth_base.join(path)?)
+    }
+
+    /// Resolve the path of an API endpoint against the API base.
+    pub fn api_url(&self, path: &str) -> Result<Url> {
+        Ok(self.api_base.join(path)?)
+    }
+}
+
+
+impl Default for Endpoints {
+    fn default() -> Endpoints {
+        Endpoints::new(DEFAULT_OAUTH_BASE, DEFAULT_API_BASE).unwrap()
+    }
+}
+
+
+/// Parses the base URL, making sure its path ends with a slash so that the
+/// last path segment is kept when joining.
+fn normalize_base<U: IntoUrl>(base: U) -> Result<Url> {
+    let mut base = base.into_url()?;
+    if !base.path().ends_with('/') {
+        let path = format!("{}/", base.path());
+        base.set_path(&path);
+    }
+    Ok(base)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_default_endpoints() {
+        let endpoints = Endpoints::default();
+        assert_eq!(endpoints.oauth_url("emp/oauth2/v3/access_token").unwrap().as_str(),
+                   "https://oauth.api.189.cn/emp/oauth2/v3/access_token");
+        assert_eq!(endpoints.api_url("v2/dm/randcode/token").unwrap().as_str(),
+                   "http://api.189.cn/v2/dm/randcode/token");
+    }
+
+
+    #[test]
+    fn test_override_base() {
+        let mut endpoints = Endpoints::default();
+        endpoints.set_oauth_base("http://127.0.0.1:8080").unwrap();
+        endpoints.set_api_base("http://127.0.0.1:8081/prefix").unwrap();
+        assert_eq!(endpoints.oauth_url("emp/oauth2/v3/authorize").unwrap().as_str(),
+                   "http://127.0.0.1:8080/emp/oauth2/v3/authorize");
+        assert_eq!(endpoints.api_url("v2/dm/randcode/token").unwrap().as_str(),
+                   "http://127.0.0.1:8081/prefix/v2/dm/randcode/token");
+
+        assert!(endpoints.set_api_base("not a url").is_err());
+    }
+}
@@ -1,7 +1,14 @@
 //! [`Result`] and [`Error`] types for this crate, powered by `error-chain`.
 //!
+//! Errors reported by the API carry a raw `res_code`; [`ApiErrorCode`] gives
+//! the documented ones names, and the helper methods on [`Error`] classify
+//! errors for deciding what to do next.
+//!
 //! [`Result`]: type.Result.html
 //! [`Error`]: struct.Error.html
+//! [`ApiErrorCode`]: enum.ApiErrorCode.html
+
+use std::fmt;
 
 
 error_chain! {
@@ -28,10 +35,62 @@ error_chain! {
         }
 
         /// Validation error: the verification code provided has non-digit characters
-        /// in it.
-        NonDigitInSmsCode(code: String) {
+        /// in it. Only the position of the first one is kept, lest the code is
+        /// logged.
+        NonDigitInSmsCode(position: usize) {
             description("non-digit character found in SMS verification code")
-            display("non-digit char in SMS verification code: {:?}", code)
+            display("non-digit char at position {} in SMS verification code", position)
+        }
+
+        /// Validation error: the phone number isn't a valid Mainland China mobile
+        /// number.
+        InvalidPhoneNumber(phone: String) {
+            description("invalid phone number")
+            display("invalid phone number: {:?}", phone)
+        }
+
+        /// Validation error: a template SMS parameter name is empty or has characters
+        /// other than ASCII alphanumerics and underscores in it.
+        InvalidTemplateParamName(name: String) {
+            description("invalid template SMS parameter name")
+            display("invalid template SMS parameter name: {:?}", name)
+        }
+
+        /// Validation error: a template SMS parameter has an empty value.
+        EmptyTemplateParamValue(name: String) {
+            description("empty template SMS parameter value")
+            display("empty value for template SMS parameter {:?}", name)
+        }
+
+        /// The request timed out.
+        Timeout(kind: TimeoutKind) {
+            description("request timed out")
+            display("request timed out: {}", kind.description())
+        }
+
+        /// Sending is rejected locally by the rate limiter; retry after the given
+        /// amount of time.
+        RateLimited(retry_after: ::std::time::Duration) {
+            description("rate limited")
+            display("rate limited, retry after {:?}", retry_after)
+        }
+
+        /// Callback error: a required parameter is missing from the request.
+        MissingCallbackParam(name: String) {
+            description("missing parameter in callback request")
+            display("missing parameter in callback request: {:?}", name)
+        }
+
+        /// The client configuration is invalid.
+        InvalidConfig(error: ConfigError) {
+            description("invalid client configuration")
+            display("invalid client configuration: {}", error)
+        }
+
+        /// Callback error: the request signature doesn't match.
+        InvalidSignature {
+            description("invalid request signature")
+            display("invalid request signature")
         }
     }
 
@@ -42,3 +101,233 @@ error_chain! {
         HyperParseError(::hyper::error::ParseError) #[doc="Hyper parsing error."];
     }
 }
+
+
+/// The stage at which a request timed out.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum TimeoutKind {
+    /// The connection couldn't be established in time. The request is
+    /// guaranteed to have not been sent.
+    Connect,
+    /// Sending the request or receiving the response took too long.
+    Response,
+    /// The per-call deadline passed before the call completed.
+    Deadline,
+}
+
+
+impl TimeoutKind {
+    /// Get a short description of the timeout.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            TimeoutKind::Connect => "connect timeout",
+            TimeoutKind::Response => "response timeout",
+            TimeoutKind::Deadline => "deadline exceeded",
+        }
+    }
+}
+
+
+/// A problem with the client configuration, found when building the client.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum ConfigError {
+    /// The app ID is empty.
+    EmptyAppId,
+    /// The app secret is empty.
+    EmptySecret,
+    /// A base URL couldn't be parsed.
+    InvalidBaseUrl(String),
+    /// The user agent is empty, or has characters not allowed in HTTP headers
+    /// in it.
+    InvalidUserAgent(String),
+    /// Timeouts are set alongside a custom HTTP transport, which they can't
+    /// be applied to.
+    TimeoutsWithCustomTransport,
+    /// The retry policy allows no attempts at all.
+    ZeroMaxAttempts,
+}
+
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::EmptyAppId => write!(f, "empty app ID"),
+            ConfigError::EmptySecret => write!(f, "empty app secret"),
+            ConfigError::InvalidBaseUrl(ref url) => write!(f, "invalid base URL {:?}", url),
+            ConfigError::InvalidUserAgent(ref ua) => write!(f, "invalid user agent {:?}", ua),
+            ConfigError::TimeoutsWithCustomTransport => {
+                write!(f, "timeouts can't be applied to a custom transport")
+            }
+            ConfigError::ZeroMaxAttempts => write!(f, "retry policy allows no attempts"),
+        }
+    }
+}
+
+
+/// Typed `res_code` values reported by the `open.189.cn` API.
+///
+/// Only the codes the client itself acts on are typed: it retries calls
+/// failing with [`SystemBusy`] if the retry policy allows, re-signs requests
+/// rejected with [`TimestampExpired`] when correcting for clock skew, and
+/// renews access tokens rejected with [`InvalidAccessToken`] or
+/// [`AccessTokenExpired`]. This crate has no documented meaning to vouch for
+/// any other code, so they're all reported as [`Other`]; look them up in the
+/// platform's documentation.
+///
+/// [`SystemBusy`]: #variant.SystemBusy
+/// [`TimestampExpired`]: #variant.TimestampExpired
+/// [`InvalidAccessToken`]: #variant.InvalidAccessToken
+/// [`AccessTokenExpired`]: #variant.AccessTokenExpired
+/// [`Other`]: #variant.Other
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ApiErrorCode {
+    /// `res_code` 1: the API server is busy or failed internally.
+    SystemBusy,
+    /// `res_code` 103: the request timestamp is too far off from the
+    /// server's clock.
+    TimestampExpired,
+    /// `res_code` 110: the access token is invalid.
+    InvalidAccessToken,
+    /// `res_code` 111: the access token has expired.
+    AccessTokenExpired,
+    /// Any other `res_code`.
+    Other(u64),
+}
+
+
+impl ApiErrorCode {
+    /// Get the raw `res_code` value.
+    pub fn code(&self) -> u64 {
+        match *self {
+            ApiErrorCode::SystemBusy => 1,
+            ApiErrorCode::TimestampExpired => 103,
+            ApiErrorCode::InvalidAccessToken => 110,
+            ApiErrorCode::AccessTokenExpired => 111,
+            ApiErrorCode::Other(code) => code,
+        }
+    }
+
+    /// Whether the same request may succeed if simply tried again.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            ApiErrorCode::SystemBusy => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the error is about the access token.
+    pub fn is_auth_error(&self) -> bool {
+        self.is_token_error()
+    }
+
+    /// Whether the access token in use should be replaced with a new one.
+    pub fn is_token_error(&self) -> bool {
+        match *self {
+            ApiErrorCode::InvalidAccessToken |
+            ApiErrorCode::AccessTokenExpired => true,
+            _ => false,
+        }
+    }
+}
+
+
+impl From<u64> for ApiErrorCode {
+    fn from(code: u64) -> ApiErrorCode {
+        match code {
+            1 => ApiErrorCode::SystemBusy,
+            103 => ApiErrorCode::TimestampExpired,
+            110 => ApiErrorCode::InvalidAccessToken,
+            111 => ApiErrorCode::AccessTokenExpired,
+            code => ApiErrorCode::Other(code),
+        }
+    }
+}
+
+
+impl Error {
+    /// Get the typed `res_code` if the error is reported by the API.
+    pub fn api_error_code(&self) -> Option<ApiErrorCode> {
+        match *self.kind() {
+            ErrorKind::ApiError(_, retcode, _, _) => Some(retcode.into()),
+            _ => None,
+        }
+    }
+
+    /// Whether the same request may succeed if simply tried again.
+    ///
+    /// This is the case for transport-level failures and timeouts, server-side
+    /// errors, and the API reporting itself as busy.
+    pub fn is_retryable(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::IoError(_) |
+            ErrorKind::HyperError(_) |
+            ErrorKind::Timeout(_) => true,
+            ErrorKind::ApiError(http_status, retcode, _, _) => {
+                http_status.is_server_error() || ApiErrorCode::from(retcode).is_retryable()
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the API rejected the access token.
+    pub fn is_auth_error(&self) -> bool {
+        self.api_error_code().map_or(false, |code| code.is_auth_error())
+    }
+
+    /// Whether the request itself is at fault, so it won't succeed without
+    /// changes.
+    ///
+    /// This covers requests failing local validation, and requests the API
+    /// rejects with an HTTP client error status.
+    pub fn is_client_fault(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::WrongSmsCodeLength(..) |
+            ErrorKind::NonDigitInSmsCode(_) |
+            ErrorKind::InvalidPhoneNumber(_) |
+            ErrorKind::InvalidTemplateParamName(_) |
+            ErrorKind::EmptyTemplateParamValue(_) |
+            ErrorKind::InvalidConfig(_) |
+            ErrorKind::HyperParseError(_) => true,
+            ErrorKind::ApiError(http_status, _, _, _) => http_status.is_client_error(),
+            _ => false,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_api_error_code_roundtrip() {
+        for code in 0..300 {
+            assert_eq!(ApiErrorCode::from(code).code(), code);
+        }
+        assert_eq!(ApiErrorCode::from(110), ApiErrorCode::InvalidAccessToken);
+        assert_eq!(ApiErrorCode::from(204), ApiErrorCode::Other(204));
+    }
+
+
+    #[test]
+    fn test_error_classification() {
+        let status = ::hyper::status::StatusCode::Ok;
+        let e: Error = ErrorKind::ApiError(status, 111, None, None).into();
+        assert_eq!(e.api_error_code(), Some(ApiErrorCode::AccessTokenExpired));
+        assert!(e.is_auth_error());
+        assert!(!e.is_retryable());
+        assert!(!e.is_client_fault());
+
+        let status = ::hyper::status::StatusCode::BadRequest;
+        let e: Error = ErrorKind::ApiError(status, 204, None, None).into();
+        assert_eq!(e.api_error_code(), Some(ApiErrorCode::Other(204)));
+        assert!(!e.is_auth_error());
+        assert!(!e.is_retryable());
+        assert!(e.is_client_fault());
+
+        let e: Error = ErrorKind::NonDigitInSmsCode(5).into();
+        assert_eq!(e.api_error_code(), None);
+        assert!(e.is_client_fault());
+    }
+}
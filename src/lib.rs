@@ -9,9 +9,14 @@ extern crate chrono;
 extern crate crypto;
 #[macro_use]
 extern crate error_chain;
+extern crate fs2;
+extern crate futures;
+extern crate futures_cpupool;
 extern crate hyper;
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate log;
 extern crate rand;
 extern crate rustc_serialize;
 extern crate serde;
@@ -21,11 +26,32 @@ extern crate serde_json;
 extern crate url;
 
 mod app;
+mod async_app;
+pub mod callback;
+pub mod clock;
+mod endpoints;
 pub mod errors;
+pub mod metrics;
+pub mod middleware;
+#[cfg(feature = "mock")]
+pub mod mock;
 pub mod msg;
 mod net;
+pub mod oauth;
+pub mod phone;
+pub mod ratelimit;
+mod redact;
+mod req;
 mod resp;
-mod sig;
+pub mod retry;
+pub mod signature;
+pub mod token;
+mod token_cache;
+pub mod transport;
 mod util;
+pub mod verification;
 
 pub use app::*;
+pub use async_app::*;
+pub use endpoints::Endpoints;
+pub use req::{CallbackSmsCode, PreparedSmsCode, SmsCodeConfig};
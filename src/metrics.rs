@@ -0,0 +1,317 @@
+//! Metrics of API calls.
+//!
+//! Every call the client makes is reported to a [`MetricsRecorder`] once it
+//! completes, retries included, with the endpoint, the outcome and the time
+//! taken. Nothing is recorded by default; install a recorder with
+//! [`Open189AppBuilder::metrics_recorder`] to feed your own metrics system,
+//! or use the built-in [`InMemoryRecorder`], which renders the Prometheus
+//! text exposition format.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use open189::Open189AppBuilder;
+//! use open189::metrics::InMemoryRecorder;
+//!
+//! let recorder = Arc::new(InMemoryRecorder::new());
+//! let client = Open189AppBuilder::new("your app id here", "your app secret here")
+//!     .metrics_recorder(recorder.clone())
+//!     .build()
+//!     .unwrap();
+//!
+//! // serve this on your metrics endpoint
+//! let exposition = recorder.render_prometheus();
+//! ```
+//!
+//! [`MetricsRecorder`]: trait.MetricsRecorder.html
+//! [`Open189AppBuilder::metrics_recorder`]: ../struct.Open189AppBuilder.html#method.metrics_recorder
+//! [`InMemoryRecorder`]: struct.InMemoryRecorder.html
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::status::StatusCode;
+
+/// Default upper bounds of the latency histogram buckets, in seconds.
+const DEFAULT_BUCKETS: &'static [f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+                                          5.0, 10.0];
+
+
+/// A completed API call.
+#[derive(Clone, Debug)]
+pub struct CallMetrics<'a> {
+    /// The endpoint called, as its path relative to the base URL, e.g.
+    /// `v2/dm/randcode/token`; it's the same whatever base URL is in use.
+    pub endpoint: &'a str,
+    /// The HTTP status of the last response, unless none was received.
+    pub status: Option<StatusCode>,
+    /// The `res_code` of the last response: `0` for a successful call, or
+    /// the API error code. `None` if the call failed otherwise.
+    pub res_code: Option<u64>,
+    /// Time taken by the call, retries included.
+    pub duration: Duration,
+    /// Number of retries made.
+    pub retries: u32,
+}
+
+
+/// A receiver of API call metrics.
+///
+/// Recorders are shared between threads, and called on the request path, so
+/// they should be quick.
+pub trait MetricsRecorder: Send + Sync {
+    /// Record a completed call.
+    fn record(&self, call: &CallMetrics);
+}
+
+
+impl<M: MetricsRecorder + ?Sized> MetricsRecorder for Arc<M> {
+    fn record(&self, call: &CallMetrics) {
+        (**self).record(call)
+    }
+}
+
+
+/// A recorder discarding all metrics; the default.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct NoopRecorder;
+
+
+impl MetricsRecorder for NoopRecorder {
+    fn record(&self, _call: &CallMetrics) {}
+}
+
+
+/// A recorder aggregating metrics in memory.
+///
+/// Per endpoint, it keeps call counts by HTTP status and `res_code`, retry
+/// counts, and a latency histogram, all rendered by [`render_prometheus`] as:
+///
+/// * `open189_requests_total{endpoint, status, res_code}`, a counter; calls
+///   without a response or `res_code` are labeled `none`, so errors are the
+///   calls with `res_code` other than `0`;
+/// * `open189_request_retries_total{endpoint}`, a counter;
+/// * `open189_request_duration_seconds{endpoint}`, a histogram.
+///
+/// [`render_prometheus`]: #method.render_prometheus
+#[derive(Debug)]
+pub struct InMemoryRecorder {
+    buckets: Vec<f64>,
+    endpoints: Mutex<BTreeMap<String, EndpointMetrics>>,
+}
+
+
+#[derive(Debug, Default)]
+struct EndpointMetrics {
+    requests: BTreeMap<(String, String), u64>,
+    retries: u64,
+    bucket_counts: Vec<u64>,
+    duration_sum: f64,
+    count: u64,
+}
+
+
+impl InMemoryRecorder {
+    /// Construct a recorder with the default latency buckets, ranging from
+    /// 5 milliseconds to 10 seconds.
+    pub fn new() -> InMemoryRecorder {
+        InMemoryRecorder::with_buckets(DEFAULT_BUCKETS.to_vec())
+    }
+
+    /// Construct a recorder with the given upper bounds of the latency
+    /// buckets, in seconds.
+    pub fn with_buckets(mut buckets: Vec<f64>) -> InMemoryRecorder {
+        buckets.retain(|b| b.is_finite());
+        buckets.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        buckets.dedup();
+        InMemoryRecorder {
+            buckets: buckets,
+            endpoints: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Get the number of calls recorded for the endpoint.
+    pub fn request_count(&self, endpoint: &str) -> u64 {
+        let endpoints = self.endpoints.lock().unwrap();
+        endpoints.get(endpoint).map_or(0, |m| m.count)
+    }
+
+    /// Get the number of calls recorded for the endpoint that ended with
+    /// the given `res_code`, `0` meaning success.
+    pub fn res_code_count(&self, endpoint: &str, res_code: u64) -> u64 {
+        let res_code = res_code.to_string();
+        let endpoints = self.endpoints.lock().unwrap();
+        endpoints.get(endpoint).map_or(0, |m| {
+            m.requests
+                .iter()
+                .filter(|&(&(_, ref code), _)| *code == res_code)
+                .map(|(_, &count)| count)
+                .sum()
+        })
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let endpoints = self.endpoints.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP open189_requests_total Calls to the open.189.cn API.\n");
+        out.push_str("# TYPE open189_requests_total counter\n");
+        for (endpoint, m) in endpoints.iter() {
+            for (&(ref status, ref res_code), count) in &m.requests {
+                let _ = writeln!(out,
+                                 "open189_requests_total{{endpoint=\"{}\",status=\"{}\",\
+                                  res_code=\"{}\"}} {}",
+                                 escape_label(endpoint),
+                                 status,
+                                 res_code,
+                                 count);
+            }
+        }
+
+        out.push_str("# HELP open189_request_retries_total Retries of calls to the open.189.cn \
+                      API.\n");
+        out.push_str("# TYPE open189_request_retries_total counter\n");
+        for (endpoint, m) in endpoints.iter() {
+            let _ = writeln!(out,
+                             "open189_request_retries_total{{endpoint=\"{}\"}} {}",
+                             escape_label(endpoint),
+                             m.retries);
+        }
+
+        out.push_str("# HELP open189_request_duration_seconds Duration of calls to the \
+                      open.189.cn API, retries included.\n");
+        out.push_str("# TYPE open189_request_duration_seconds histogram\n");
+        for (endpoint, m) in endpoints.iter() {
+            let endpoint = escape_label(endpoint);
+            let mut cumulative = 0;
+            for (bound, count) in self.buckets.iter().zip(&m.bucket_counts) {
+                cumulative += *count;
+                let _ = writeln!(out,
+                                 "open189_request_duration_seconds_bucket{{endpoint=\"{}\",\
+                                  le=\"{}\"}} {}",
+                                 endpoint,
+                                 bound,
+                                 cumulative);
+            }
+            let _ = writeln!(out,
+                             "open189_request_duration_seconds_bucket{{endpoint=\"{}\",\
+                              le=\"+Inf\"}} {}",
+                             endpoint,
+                             m.count);
+            let _ = writeln!(out,
+                             "open189_request_duration_seconds_sum{{endpoint=\"{}\"}} {}",
+                             endpoint,
+                             m.duration_sum);
+            let _ = writeln!(out,
+                             "open189_request_duration_seconds_count{{endpoint=\"{}\"}} {}",
+                             endpoint,
+                             m.count);
+        }
+
+        out
+    }
+}
+
+
+impl Default for InMemoryRecorder {
+    fn default() -> InMemoryRecorder {
+        InMemoryRecorder::new()
+    }
+}
+
+
+impl MetricsRecorder for InMemoryRecorder {
+    fn record(&self, call: &CallMetrics) {
+        let status = call.status.map_or("none".to_string(), |s| s.to_u16().to_string());
+        let res_code = call.res_code.map_or("none".to_string(), |c| c.to_string());
+        let secs = call.duration.as_secs() as f64 + call.duration.subsec_nanos() as f64 / 1e9;
+
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let m = endpoints.entry(call.endpoint.to_string()).or_insert_with(Default::default);
+        if m.bucket_counts.is_empty() {
+            m.bucket_counts = vec![0; self.buckets.len()];
+        }
+
+        *m.requests.entry((status, res_code)).or_insert(0) += 1;
+        m.retries += call.retries as u64;
+        if let Some(i) = self.buckets.iter().position(|&bound| secs <= bound) {
+            m.bucket_counts[i] += 1;
+        }
+        m.duration_sum += secs;
+        m.count += 1;
+    }
+}
+
+
+/// Escapes a Prometheus label value.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn call(res_code: Option<u64>, millis: u64, retries: u32) -> CallMetrics<'static> {
+        CallMetrics {
+            endpoint: "v2/dm/randcode/token",
+            status: res_code.map(|_| StatusCode::Ok),
+            res_code: res_code,
+            duration: Duration::from_millis(millis),
+            retries: retries,
+        }
+    }
+
+
+    #[test]
+    fn test_in_memory_recorder() {
+        let recorder = InMemoryRecorder::with_buckets(vec![1.0, 0.1]);
+        recorder.record(&call(Some(0), 50, 0));
+        recorder.record(&call(Some(110), 500, 1));
+        recorder.record(&call(None, 2000, 2));
+
+        assert_eq!(recorder.request_count("v2/dm/randcode/token"), 3);
+        assert_eq!(recorder.res_code_count("v2/dm/randcode/token", 110), 1);
+        assert_eq!(recorder.request_count("v2/dm/randcode/sendSms"), 0);
+
+        assert_eq!(recorder.render_prometheus(),
+                   "# HELP open189_requests_total Calls to the open.189.cn API.\n\
+                    # TYPE open189_requests_total counter\n\
+                    open189_requests_total{endpoint=\"v2/dm/randcode/token\",status=\"200\",\
+                    res_code=\"0\"} 1\n\
+                    open189_requests_total{endpoint=\"v2/dm/randcode/token\",status=\"200\",\
+                    res_code=\"110\"} 1\n\
+                    open189_requests_total{endpoint=\"v2/dm/randcode/token\",status=\"none\",\
+                    res_code=\"none\"} 1\n\
+                    # HELP open189_request_retries_total Retries of calls to the open.189.cn \
+                    API.\n\
+                    # TYPE open189_request_retries_total counter\n\
+                    open189_request_retries_total{endpoint=\"v2/dm/randcode/token\"} 3\n\
+                    # HELP open189_request_duration_seconds Duration of calls to the \
+                    open.189.cn API, retries included.\n\
+                    # TYPE open189_request_duration_seconds histogram\n\
+                    open189_request_duration_seconds_bucket{endpoint=\"v2/dm/randcode/token\",\
+                    le=\"0.1\"} 1\n\
+                    open189_request_duration_seconds_bucket{endpoint=\"v2/dm/randcode/token\",\
+                    le=\"1\"} 2\n\
+                    open189_request_duration_seconds_bucket{endpoint=\"v2/dm/randcode/token\",\
+                    le=\"+Inf\"} 3\n\
+                    open189_request_duration_seconds_sum{endpoint=\"v2/dm/randcode/token\"} \
+                    2.55\n\
+                    open189_request_duration_seconds_count{endpoint=\"v2/dm/randcode/token\"} \
+                    3\n");
+    }
+
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
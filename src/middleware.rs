@@ -0,0 +1,99 @@
+//! Request/response middleware.
+//!
+//! Install [`Middleware`] layers with [`Open189AppBuilder::middleware`] to
+//! take part in every request the client makes, including retries: a layer
+//! may add or change the request parameters before they're signed, edit the
+//! request headers right before it's sent, and observe the outcome, with
+//! the raw response, the latency and the `res_code` or error it was parsed
+//! into. This is the place for auditing, header injection or custom metrics.
+//!
+//! Layers are called in the order they're installed on the way out, and in
+//! reverse order on the way back, so that the first layer installed wraps
+//! all the others. The [`on_request`] and [`on_response`] hooks of the
+//! builder make up the innermost layer, seeing requests exactly as sent.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//!
+//! use open189::Open189AppBuilder;
+//! use open189::middleware::{Exchange, Middleware};
+//!
+//! #[derive(Default)]
+//! struct CountFailures(AtomicUsize);
+//!
+//! impl Middleware for CountFailures {
+//!     fn after_response(&self, exchange: &Exchange) {
+//!         if exchange.error.is_some() {
+//!             self.0.fetch_add(1, Ordering::SeqCst);
+//!         }
+//!     }
+//! }
+//!
+//! let client = Open189AppBuilder::new("your app id here", "your app secret here")
+//!     .middleware(CountFailures::default())
+//!     .build()
+//!     .unwrap();
+//! ```
+//!
+//! [`Middleware`]: trait.Middleware.html
+//! [`Open189AppBuilder::middleware`]: ../struct.Open189AppBuilder.html#method.middleware
+//! [`on_request`]: ../struct.Open189AppBuilder.html#method.on_request
+//! [`on_response`]: ../struct.Open189AppBuilder.html#method.on_response
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::errors::*;
+use super::transport::{HttpRequest, HttpResponse};
+
+
+/// A layer of the middleware chain.
+///
+/// Every method does nothing by default, so implement only the ones you
+/// need. Layers are shared between threads, so they must synchronize any
+/// state they keep.
+pub trait Middleware: Send + Sync {
+    /// Called with the parameters of an outgoing request, before they're
+    /// signed.
+    ///
+    /// The `app_id`, `access_token` and `timestamp` parameters are already
+    /// present; changes to any parameter are covered by the signature. For
+    /// OAuth access token requests, which aren't signed, the parameters
+    /// include the app secret.
+    fn prepare_params(&self, params: &mut HashMap<&'static str, String>) {
+        let _ = params;
+    }
+
+    /// Called with the request right before it's sent.
+    fn before_send(&self, request: &mut HttpRequest) {
+        let _ = request;
+    }
+
+    /// Called with the outcome of the request.
+    fn after_response(&self, exchange: &Exchange) {
+        let _ = exchange;
+    }
+}
+
+
+/// The outcome of a request, as observed by [`Middleware`].
+///
+/// [`Middleware`]: trait.Middleware.html
+#[derive(Debug)]
+pub struct Exchange<'a> {
+    /// The request as sent.
+    pub request: &'a HttpRequest,
+    /// The raw response, unless the request failed before one was received.
+    pub response: Option<&'a HttpResponse>,
+    /// Time taken from sending the request to receiving the response, or
+    /// failing.
+    pub latency: Duration,
+    /// The `res_code` of the response: `0` if the call succeeded, or the API
+    /// error code. `None` if there's no response, or it couldn't be parsed.
+    pub res_code: Option<u64>,
+    /// The error the request resulted in, if any, be it a transport failure,
+    /// a malformed response or an error reported by the API.
+    pub error: Option<&'a Error>,
+}
@@ -0,0 +1,773 @@
+//! A local stand-in for the `open.189.cn` API, for testing.
+//!
+//! [`MockServer`] runs an HTTP server on the loopback interface, emulating
+//! the OAuth access token, SMS token and verification code sending endpoints.
+//! Requests are validated like the real API would, signatures included; fake
+//! tokens are issued, and "sent" SMS are recorded instead of delivered, so
+//! tests can assert on them. Specific `res_code`s can be scripted to exercise
+//! error handling.
+//!
+//! This module is only available with the `mock` feature enabled; so are
+//! the client's integration tests, run with `cargo test --features mock`.
+//!
+//! [`MockServer`]: struct.MockServer.html
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{BufReader, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{Connection, ContentType, Headers};
+use hyper::method::Method;
+use hyper::net::{HttpStream, NetworkStream};
+use hyper::server::{Handler, Request, Response};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+
+use url::form_urlencoded;
+
+use super::app::{Open189App, Open189AppBuilder};
+use super::endpoints::Endpoints;
+use super::errors::*;
+use super::req;
+use super::signature;
+use super::util;
+
+/// Lifetime of the access tokens issued, in seconds.
+const ACCESS_TOKEN_EXPIRES_IN: u64 = 2592000;
+/// Maximum deviation of request timestamps from the server's clock, in
+/// seconds.
+const TIMESTAMP_TOLERANCE_SECS: u64 = 300;
+/// Time to wait for a client to send its request, in seconds.
+const READ_TIMEOUT_SECS: u64 = 5;
+
+
+/// The emulated endpoints.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum MockEndpoint {
+    /// The OAuth access token endpoint.
+    AccessToken,
+    /// The SMS token endpoint.
+    SmsToken,
+    /// The endpoint for sending locally generated verification codes.
+    SendSms,
+}
+
+
+/// A verification code SMS recorded by the mock server.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SentSms {
+    /// The recipient's phone number.
+    pub phone: String,
+    /// The verification code.
+    pub code: String,
+    /// The expiry time of the code in minutes, if given.
+    pub expire_time: Option<usize>,
+    /// The identifier returned for the SMS.
+    pub sms_id: String,
+}
+
+
+#[derive(Debug, Default)]
+struct State {
+    access_tokens: HashSet<String>,
+    sms_tokens: HashSet<String>,
+    sent: Vec<SentSms>,
+    scripted: HashMap<MockEndpoint, VecDeque<u64>>,
+    next_id: u64,
+}
+
+
+impl State {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn issue_access_token(&mut self) -> String {
+        let token = format!("mock-access-token-{}", self.next_id());
+        self.access_tokens.insert(token.clone());
+        token
+    }
+}
+
+
+/// Mock `open.189.cn` API server.
+///
+/// The server accepts a single app, identified by the app ID and secret it's
+/// started with. Requests are served one at a time, on a connection each,
+/// by a thread of its own. When the server is dropped, the thread finishes
+/// the request at hand if any, then closes the socket and exits.
+///
+/// # Examples
+///
+/// ```
+/// use open189::SmsCodeConfig;
+/// use open189::mock::MockServer;
+///
+/// let server = MockServer::start("123", "secret").unwrap();
+/// let client = server.app();
+///
+/// let access_token = client.get_access_token_cc().unwrap();
+/// let sms_token = client.sms_get_token(&access_token.token).unwrap();
+/// let config = SmsCodeConfig::prepared("18912345678", "123456", None).unwrap();
+/// client.sms_send_verification_code(&access_token.token, &sms_token, config).unwrap();
+///
+/// assert_eq!(server.sent_sms()[0].code, "123456");
+/// ```
+pub struct MockServer {
+    app_id: String,
+    secret: String,
+    endpoints: Endpoints,
+    state: Arc<Mutex<State>>,
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+
+impl MockServer {
+    /// Start a server for the given app ID and secret, listening on a random
+    /// port of the loopback interface.
+    pub fn start<S: AsRef<str>>(app_id: S, secret: S) -> Result<MockServer> {
+        let state = Arc::new(Mutex::new(State::default()));
+        let handler = MockHandler {
+            app_id: app_id.as_ref().to_string(),
+            secret: secret.as_ref().to_string(),
+            state: state.clone(),
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let base = format!("http://{}/", addr);
+        let endpoints = Endpoints::new(base.as_str(), base.as_str())?;
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        handler.serve(stream);
+                    }
+                }
+            })
+        };
+
+        Ok(MockServer {
+            app_id: app_id.as_ref().to_string(),
+            secret: secret.as_ref().to_string(),
+            endpoints: endpoints,
+            state: state,
+            addr: addr,
+            shutdown: shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    /// Get the endpoints pointing to the server.
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    /// Construct a client talking to the server with the server's app ID and
+    /// secret.
+    pub fn app(&self) -> Open189App {
+        let mut app = Open189App::new(self.app_id.as_str(), self.secret.as_str());
+        app.set_endpoints(self.endpoints.clone());
+        app
+    }
+
+    /// Start building a client talking to the server with the server's app
+    /// ID and secret, for configuring it further.
+    pub fn builder(&self) -> Open189AppBuilder {
+        Open189AppBuilder::new(self.app_id.as_str(), self.secret.as_str())
+            .endpoints(self.endpoints.clone())
+    }
+
+    /// Issue an access token directly, bypassing the OAuth endpoint.
+    pub fn issue_access_token(&self) -> String {
+        self.state.lock().unwrap().issue_access_token()
+    }
+
+    /// Make the next request to the endpoint fail with the given `res_code`.
+    ///
+    /// Scripted codes are queued per endpoint and consumed in order, one per
+    /// request; a code of 0 lets the request through as usual.
+    pub fn script(&self, endpoint: MockEndpoint, res_code: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.scripted.entry(endpoint).or_insert_with(VecDeque::new).push_back(res_code);
+    }
+
+    /// Get the SMS sent so far, in order.
+    pub fn sent_sms(&self) -> Vec<SentSms> {
+        self.state.lock().unwrap().sent.clone()
+    }
+}
+
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // wake the thread up from accepting connections; it closes the
+        // listening socket on its way out
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+#[derive(Serialize, Default)]
+struct MockResponse {
+    res_code: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    res_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    access_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    create_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identifier: Option<String>,
+}
+
+
+impl MockResponse {
+    fn error<S: AsRef<str>>(res_code: u64, message: S) -> MockResponse {
+        MockResponse {
+            res_code: res_code,
+            res_message: Some(message.as_ref().to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+
+struct MockHandler {
+    app_id: String,
+    secret: String,
+    state: Arc<Mutex<State>>,
+}
+
+
+impl MockHandler {
+    /// Serves a single request on the connection, then closes it.
+    fn serve(&self, stream: TcpStream) {
+        let addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(_) => return,
+        };
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS)));
+        let mut reader = match stream.try_clone() {
+            Ok(stream) => HttpStream(stream),
+            Err(_) => return,
+        };
+        let mut writer = HttpStream(stream);
+
+        let mut reader = BufReader::new(&mut reader as &mut NetworkStream);
+        let req = match Request::new(&mut reader, addr) {
+            Ok(req) => req,
+            Err(_) => return,
+        };
+        let mut headers = Headers::new();
+        headers.set(Connection::close());
+        self.handle(req, Response::new(&mut writer, &mut headers));
+    }
+
+    fn access_token(&self, state: &mut State, params: &HashMap<String, String>) -> MockResponse {
+        let oauth_state = params.get("state").cloned().unwrap_or_default();
+        let mut response = self.check_access_token_request(params).unwrap_or_else(|| {
+            MockResponse {
+                access_token: Some(state.issue_access_token()),
+                expires_in: Some(ACCESS_TOKEN_EXPIRES_IN),
+                ..Default::default()
+            }
+        });
+        response.state = Some(oauth_state);
+        response
+    }
+
+    fn check_access_token_request(&self,
+                                  params: &HashMap<String, String>)
+                                  -> Option<MockResponse> {
+        if params.get("app_id") != Some(&self.app_id) ||
+           params.get("app_secret") != Some(&self.secret) {
+            return Some(MockResponse::error(101, "invalid app_id or app_secret"));
+        }
+        if params.get("grant_type").map(String::as_str) != Some("client_credentials") {
+            return Some(MockResponse::error(100, "unsupported grant_type"));
+        }
+        None
+    }
+
+    fn sms_token(&self, state: &mut State, params: &HashMap<String, String>) -> MockResponse {
+        if let Some(response) = self.check_signed_request(state, params) {
+            return response;
+        }
+
+        let token = format!("mock-sms-token-{}", state.next_id());
+        state.sms_tokens.insert(token.clone());
+        MockResponse { token: Some(token), ..Default::default() }
+    }
+
+    fn send_sms(&self, state: &mut State, params: &HashMap<String, String>) -> MockResponse {
+        if let Some(response) = self.check_signed_request(state, params) {
+            return response;
+        }
+
+        let sms_token = params.get("token").map_or("", String::as_str);
+        if !state.sms_tokens.remove(sms_token) {
+            return MockResponse::error(204, "invalid token");
+        }
+
+        let (phone, code) = match (params.get("phone"), params.get("randcode")) {
+            (Some(phone), Some(code)) => (phone.clone(), code.clone()),
+            _ => return MockResponse::error(100, "missing phone or randcode"),
+        };
+        let expire_time = match params.get("exp_time").map(|t| t.parse()) {
+            Some(Ok(t)) => Some(t),
+            Some(Err(_)) => return MockResponse::error(100, "invalid exp_time"),
+            None => None,
+        };
+
+        let sms_id = format!("mock-sms-{}", state.next_id());
+        state.sent.push(SentSms {
+            phone: phone,
+            code: code,
+            expire_time: expire_time,
+            sms_id: sms_id.clone(),
+        });
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        MockResponse {
+            create_at: Some(now.as_secs()),
+            identifier: Some(sms_id),
+            ..Default::default()
+        }
+    }
+
+    /// Validates the app ID, signature and access token of the request.
+    fn check_signed_request(&self,
+                            state: &State,
+                            params: &HashMap<String, String>)
+                            -> Option<MockResponse> {
+        if params.get("app_id") != Some(&self.app_id) {
+            return Some(MockResponse::error(101, "invalid app_id"));
+        }
+        let timestamp = match params.get("timestamp") {
+            Some(timestamp) => timestamp,
+            None => return Some(MockResponse::error(100, "missing timestamp")),
+        };
+        let timestamp = match util::parse_api_timestamp(timestamp) {
+            Some(timestamp) => timestamp,
+            None => return Some(MockResponse::error(100, "invalid timestamp")),
+        };
+        let now = SystemTime::now();
+        let deviation = match now.duration_since(timestamp) {
+            Ok(d) => d,
+            Err(e) => e.duration(),
+        };
+        if deviation > Duration::from_secs(TIMESTAMP_TOLERANCE_SECS) {
+            return Some(MockResponse::error(103, "timestamp expired"));
+        }
+
+        let valid = match params.get("sign") {
+            Some(sign) => signature::verify(params, &self.secret, sign),
+            None => false,
+        };
+        if !valid {
+            return Some(MockResponse::error(102, "invalid sign"));
+        }
+
+        match params.get("access_token") {
+            Some(token) if state.access_tokens.contains(token) => None,
+            _ => Some(MockResponse::error(110, "invalid access_token")),
+        }
+    }
+}
+
+
+impl Handler for MockHandler {
+    fn handle(&self, mut req: Request, mut res: Response) {
+        let (path, query) = match req.uri {
+            RequestUri::AbsolutePath(ref uri) => {
+                let mut parts = uri.splitn(2, '?');
+                let path = parts.next().unwrap_or("").trim_left_matches('/').to_string();
+                (path, parts.next().unwrap_or("").to_string())
+            }
+            _ => {
+                *res.status_mut() = StatusCode::BadRequest;
+                return;
+            }
+        };
+
+        let (endpoint, method) = match path.as_str() {
+            req::PATH_ACCESS_TOKEN => (MockEndpoint::AccessToken, Method::Post),
+            req::PATH_SMS_TOKEN => (MockEndpoint::SmsToken, Method::Get),
+            req::PATH_SMS_SEND_WITH_CODE => (MockEndpoint::SendSms, Method::Post),
+            _ => {
+                *res.status_mut() = StatusCode::NotFound;
+                return;
+            }
+        };
+        if req.method != method {
+            *res.status_mut() = StatusCode::MethodNotAllowed;
+            return;
+        }
+
+        let params: HashMap<String, String> = if method == Method::Get {
+            form_urlencoded::parse(query.as_bytes()).into_owned().collect()
+        } else {
+            let mut body = Vec::new();
+            if req.read_to_end(&mut body).is_err() {
+                *res.status_mut() = StatusCode::BadRequest;
+                return;
+            }
+            form_urlencoded::parse(&body).into_owned().collect()
+        };
+
+        let response = {
+            let mut state = self.state.lock().unwrap();
+            let scripted = state.scripted.get_mut(&endpoint).and_then(|q| q.pop_front());
+            match scripted {
+                Some(res_code) if res_code != 0 => {
+                    let mut response = MockResponse::error(res_code, "scripted failure");
+                    if endpoint == MockEndpoint::AccessToken {
+                        response.state = Some(params.get("state").cloned().unwrap_or_default());
+                    }
+                    response
+                }
+                _ => {
+                    match endpoint {
+                        MockEndpoint::AccessToken => self.access_token(&mut state, &params),
+                        MockEndpoint::SmsToken => self.sms_token(&mut state, &params),
+                        MockEndpoint::SendSms => self.send_sms(&mut state, &params),
+                    }
+                }
+            }
+        };
+
+        let body = ::serde_json::to_vec(&response).unwrap();
+        res.headers_mut().set(ContentType::json());
+        let _ = res.send(&body);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+
+    use super::*;
+    use super::super::async_app::AsyncOpen189App;
+    use super::super::clock::FixedClock;
+    use super::super::metrics::InMemoryRecorder;
+    use super::super::middleware::{Exchange, Middleware};
+    use super::super::transport::HttpRequest;
+    use super::super::req::SmsCodeConfig;
+    use super::super::ratelimit::{Quota, RateLimiter};
+    use super::super::retry::RetryPolicy;
+    use super::super::token::{MemoryTokenStore, TokenStore};
+
+
+    fn start() -> MockServer {
+        MockServer::start("123", "secret").unwrap()
+    }
+
+
+    #[test]
+    fn test_shutdown() {
+        let server = start();
+        let addr = server.addr;
+        assert!(server.app().get_access_token_cc().is_ok());
+
+        drop(server);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+
+    #[test]
+    fn test_send_verification_code() {
+        let server = start();
+        let client = server.app();
+
+        let access_token = client.get_access_token_cc().unwrap();
+        let sms_token = client.sms_get_token(access_token.token.as_str()).unwrap();
+        let config = SmsCodeConfig::prepared("18912345678", "123456", Some(5)).unwrap();
+        let sent = client.sms_send_verification_code(access_token.token.as_str(),
+                                                     sms_token.as_str(),
+                                                     config)
+            .unwrap();
+
+        assert_eq!(server.sent_sms(),
+                   vec![SentSms {
+                            phone: "18912345678".to_string(),
+                            code: "123456".to_string(),
+                            expire_time: Some(5),
+                            sms_id: sent.sms_id,
+                        }]);
+
+        // SMS tokens are single use
+        let config = SmsCodeConfig::prepared("18912345678", "123456", Some(5)).unwrap();
+        let e = client.sms_send_verification_code(access_token.token.as_str(),
+                                                  sms_token.as_str(),
+                                                  config)
+            .unwrap_err();
+        assert_eq!(e.api_error_code(), Some(ApiErrorCode::Other(204)));
+    }
+
+
+    #[test]
+    fn test_send_verification_code_one_shot() {
+        let server = start();
+        let mut client = server.app();
+        client.set_rate_limiter(Some(RateLimiter::new(vec![Quota::per_hour(4)], vec![])));
+
+        let config = SmsCodeConfig::prepared("18912345678", "123456", None).unwrap();
+        let result = client.send_verification_code(config).unwrap();
+        assert!(result.timing.access_token.is_some());
+
+        // the access token is cached
+        let config = SmsCodeConfig::prepared("18912345678", "654321", None).unwrap();
+        let result = client.send_verification_code(config).unwrap();
+        assert!(result.timing.access_token.is_none());
+
+        // and refreshed when rejected at either step, the retried send
+        // counting once against the rate limits
+        server.script(MockEndpoint::SmsToken, 111);
+        let config = SmsCodeConfig::prepared("18912345678", "000000", None).unwrap();
+        let result = client.send_verification_code(config).unwrap();
+        assert!(result.timing.access_token.is_some());
+
+        server.script(MockEndpoint::SendSms, 111);
+        let config = SmsCodeConfig::prepared("18912345678", "111111", None).unwrap();
+        let result = client.send_verification_code(config).unwrap();
+        assert!(result.timing.access_token.is_some());
+
+        let config = SmsCodeConfig::prepared("18912345678", "222222", None).unwrap();
+        match *client.send_verification_code(config).unwrap_err().kind() {
+            ErrorKind::RateLimited(_) => {}
+            ref e => panic!("unexpected error {:?}", e),
+        }
+
+        // but only once
+        server.script(MockEndpoint::SmsToken, 111);
+        server.script(MockEndpoint::SendSms, 111);
+        let config = SmsCodeConfig::prepared("18912345679", "333333", None).unwrap();
+        let e = client.send_verification_code(config).unwrap_err();
+        assert_eq!(e.api_error_code(), Some(ApiErrorCode::AccessTokenExpired));
+
+        assert_eq!(server.sent_sms().len(), 4);
+    }
+
+
+    #[test]
+    fn test_send_verification_code_shared_store() {
+        let server = start();
+        let store = Arc::new(MemoryTokenStore::new());
+        let mut a = server.app();
+        a.set_token_store(store.clone());
+        let mut b = server.app();
+        b.set_token_store(store.clone());
+
+        let config = SmsCodeConfig::prepared("18912345678", "123456", None).unwrap();
+        assert!(a.send_verification_code(config.clone()).unwrap().timing.access_token.is_some());
+        assert!(b.send_verification_code(config).unwrap().timing.access_token.is_none());
+        assert!(store.load().unwrap().is_some());
+    }
+
+
+    #[test]
+    fn test_invalid_credentials() {
+        let server = start();
+        let access_token = server.issue_access_token();
+
+        let mut client = Open189App::new("123", "wrong secret");
+        client.set_endpoints(server.endpoints().clone());
+        let e = client.get_access_token_cc().unwrap_err();
+        assert_eq!(e.api_error_code(), Some(ApiErrorCode::Other(101)));
+        let e = client.sms_get_token(access_token.as_str()).unwrap_err();
+        assert_eq!(e.api_error_code(), Some(ApiErrorCode::Other(102)));
+
+        let e = server.app().sms_get_token("bogus").unwrap_err();
+        assert_eq!(e.api_error_code(), Some(ApiErrorCode::InvalidAccessToken));
+    }
+
+
+    #[test]
+    fn test_scripted_res_code() {
+        let server = start();
+        let client = server.app();
+        server.script(MockEndpoint::AccessToken, 1);
+        server.script(MockEndpoint::AccessToken, 0);
+
+        let e = client.get_access_token_cc().unwrap_err();
+        assert_eq!(e.api_error_code(), Some(ApiErrorCode::SystemBusy));
+        assert!(client.get_access_token_cc().is_ok());
+    }
+
+
+    #[test]
+    fn test_skew_correction() {
+        let server = start();
+        let access_token = server.issue_access_token();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+
+        let client = server.builder()
+            .clock(FixedClock::new(an_hour_ago))
+            .build()
+            .unwrap();
+        let e = client.sms_get_token(access_token.as_str()).unwrap_err();
+        assert_eq!(e.api_error_code(), Some(ApiErrorCode::TimestampExpired));
+
+        // the rejected request is re-signed with the corrected time
+        let client = server.builder()
+            .clock(FixedClock::new(an_hour_ago))
+            .skew_correction(true)
+            .build()
+            .unwrap();
+        assert!(client.sms_get_token(access_token.as_str()).is_ok());
+        assert!(client.sms_get_token(access_token.as_str()).is_ok());
+    }
+
+
+    struct Recorder {
+        name: &'static str,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+
+    impl Middleware for Recorder {
+        fn prepare_params(&self, params: &mut HashMap<&'static str, String>) {
+            params.insert(self.name, "1".to_string());
+            self.events.lock().unwrap().push(format!("{} prepare", self.name));
+        }
+
+        fn before_send(&self, request: &mut HttpRequest) {
+            request.headers.set_raw(format!("X-{}", self.name), vec![b"1".to_vec()]);
+            self.events.lock().unwrap().push(format!("{} send", self.name));
+        }
+
+        fn after_response(&self, exchange: &Exchange) {
+            assert!(exchange.request.headers.get_raw(&format!("X-{}", self.name)).is_some());
+            let status = exchange.response.map(|response| response.status.to_u16());
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("{} {:?} {:?}", self.name, status, exchange.res_code));
+        }
+    }
+
+
+    #[test]
+    fn test_middleware() {
+        let server = start();
+        let access_token = server.issue_access_token();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let request_events = events.clone();
+        let response_events = events.clone();
+
+        let client = server.builder()
+            .on_request(move |request| {
+                assert!(request.headers.get_raw("X-inner").is_some());
+                request_events.lock().unwrap().push("hook send".to_string());
+            })
+            .on_response(move |exchange| {
+                let res_code = exchange.res_code;
+                response_events.lock().unwrap().push(format!("hook {:?}", res_code));
+            })
+            .middleware(Recorder {
+                name: "outer",
+                events: events.clone(),
+            })
+            .middleware(Recorder {
+                name: "inner",
+                events: events.clone(),
+            })
+            .build()
+            .unwrap();
+
+        // the added parameters are signed
+        assert!(client.sms_get_token(access_token.as_str()).is_ok());
+        assert!(client.sms_get_token("bogus").is_err());
+        assert_eq!(*events.lock().unwrap(),
+                   vec!["outer prepare",
+                        "inner prepare",
+                        "outer send",
+                        "inner send",
+                        "hook send",
+                        "hook Some(0)",
+                        "inner Some(200) Some(0)",
+                        "outer Some(200) Some(0)",
+                        "outer prepare",
+                        "inner prepare",
+                        "outer send",
+                        "inner send",
+                        "hook send",
+                        "hook Some(110)",
+                        "inner Some(200) Some(110)",
+                        "outer Some(200) Some(110)"]);
+    }
+
+
+    #[test]
+    fn test_metrics() {
+        let server = start();
+        let access_token = server.issue_access_token();
+        let recorder = Arc::new(InMemoryRecorder::new());
+
+        let client = server.builder()
+            .metrics_recorder(recorder.clone())
+            .build()
+            .unwrap();
+        assert!(client.sms_get_token(access_token.as_str()).is_ok());
+        assert!(client.sms_get_token("bogus").is_err());
+
+        assert_eq!(recorder.request_count(req::PATH_SMS_TOKEN), 2);
+        assert_eq!(recorder.res_code_count(req::PATH_SMS_TOKEN, 110), 1);
+        assert!(recorder.render_prometheus()
+            .contains("open189_requests_total{endpoint=\"v2/dm/randcode/token\",\
+                       status=\"200\",res_code=\"0\"} 1\n"));
+    }
+
+
+    #[test]
+    fn test_async_pipeline() {
+        let server = start();
+        let recorder = Arc::new(InMemoryRecorder::new());
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let mut client = AsyncOpen189App::new("123", "secret");
+        client.set_endpoints(server.endpoints().clone());
+        client.set_retry_policy(RetryPolicy::new(2)
+            .backoff(Duration::from_millis(10), Duration::from_millis(10)));
+        client.set_metrics_recorder(recorder.clone());
+        client.add_middleware(Recorder {
+            name: "outer",
+            events: events.clone(),
+        });
+
+        // the failed attempt is retried after the delay
+        server.script(MockEndpoint::AccessToken, 1);
+        let access_token = client.get_access_token_cc().wait().unwrap().token;
+        assert!(client.sms_get_token(access_token.as_str()).wait().is_ok());
+
+        assert_eq!(recorder.request_count(req::PATH_ACCESS_TOKEN), 1);
+        assert_eq!(recorder.res_code_count(req::PATH_ACCESS_TOKEN, 0), 1);
+        assert_eq!(recorder.request_count(req::PATH_SMS_TOKEN), 1);
+        assert_eq!(events.lock().unwrap().len(), 9);
+    }
+}
@@ -1,5 +1,7 @@
 //! Types for the `open.189.cn` API responses.
 
+use std::time::Duration;
+
 /// An access token for the `open.189.cn` API.
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct AccessToken {
@@ -12,6 +14,25 @@ pub struct AccessToken {
 }
 
 
+/// An access token authorized by a user, obtained with the Authorization Code
+/// flow.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct UserAccessToken {
+    /// Nonce for distinguishing between access token requests.
+    pub state: String,
+    /// The access token returned.
+    pub token: String,
+    /// TTL of the access token, in seconds.
+    pub expires_in: u64,
+    /// Identifier of the authorizing user, unique within the app.
+    pub open_id: String,
+    /// Token for requesting a new access token after this one expires.
+    pub refresh_token: String,
+    /// The scope granted, if reported.
+    pub scope: Option<String>,
+}
+
+
 /// A summary of a successfully queued SMS verification code.
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct SentSmsCode {
@@ -20,3 +41,51 @@ pub struct SentSmsCode {
     /// API-generated unique identifier for the SMS.
     pub sms_id: String,
 }
+
+
+/// Time spent in the steps of a one-shot verification code send.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct SendTiming {
+    /// Time spent requesting an access token, or `None` if a cached one was
+    /// used.
+    pub access_token: Option<Duration>,
+    /// Time spent requesting the SMS token.
+    pub sms_token: Duration,
+    /// Time spent sending the verification code.
+    pub send: Duration,
+    /// Total time spent.
+    pub total: Duration,
+}
+
+
+/// A summary of a verification code sent with
+/// [`Open189App::send_verification_code`].
+///
+/// [`Open189App::send_verification_code`]: ../struct.Open189App.html#method.send_verification_code
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SentVerificationCode {
+    /// The summary of the queued SMS.
+    pub sent: SentSmsCode,
+    /// The time spent in each step.
+    pub timing: SendTiming,
+}
+
+
+/// An API-generated SMS verification code, as reported to the callback URL.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ReceivedSmsCode {
+    /// The phone number the code is sent to.
+    pub phone: String,
+    /// The verification code generated.
+    pub rand_code: String,
+    /// API-generated unique identifier for the SMS.
+    pub identifier: String,
+}
+
+
+/// A summary of a successfully queued template SMS.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SentTemplateSms {
+    /// API-generated unique identifier for the SMS.
+    pub sms_id: String,
+}
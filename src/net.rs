@@ -1,143 +1,866 @@
+use std::cell::Cell;
 use std::collections::HashMap;
-use std::io::Read;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::{future, BoxFuture, Future};
+use futures::future::Loop;
 
-use hyper::client::Client;
-use hyper::client::Response;
 use hyper::client::IntoUrl;
-use hyper::header::ContentType;
+use hyper::header::{ContentType, UserAgent};
 use hyper::header::Headers;
+use hyper::method::Method;
 use hyper::mime;
+use hyper::status::StatusCode;
 
 use serde::Deserialize;
 
 use url::form_urlencoded;
 
+use super::clock::{Clock, SystemClock};
 use super::errors::*;
+use super::metrics::{CallMetrics, MetricsRecorder, NoopRecorder};
+use super::middleware::{Exchange, Middleware};
+use super::redact;
 use super::resp::IntoResult;
-use super::sig;
+use super::retry::RetryPolicy;
+use super::signature;
+use super::transport::{AsyncTransport, HttpRequest, HttpResponse, Transport};
 use super::util;
 
-const URL_ACCESS_TOKEN_REQ: &'static str = "https://oauth.api.189.cn/emp/oauth2/v3/access_token";
 
+pub struct Open189Client<T> {
+    http: Arc<T>,
+    retry_policy: RetryPolicy,
+    clock: Arc<Clock>,
+    user_agent: Option<String>,
+    middleware: Vec<Arc<Middleware>>,
+    hooks: Option<Arc<Middleware>>,
+    mask_phone_numbers: bool,
+    metrics: Arc<MetricsRecorder>,
+    skew_correction: bool,
+    skew: Mutex<Option<Skew>>,
+}
+
+
+/// Offset of the API server's clock from ours.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Skew {
+    Ahead(Duration),
+    Behind(Duration),
+}
+
+
+impl Skew {
+    fn between(server_time: SystemTime, local_time: SystemTime) -> Skew {
+        match server_time.duration_since(local_time) {
+            Ok(d) => Skew::Ahead(d),
+            Err(e) => Skew::Behind(e.duration()),
+        }
+    }
+
+    fn apply(&self, time: SystemTime) -> SystemTime {
+        match *self {
+            Skew::Ahead(d) => time + d,
+            Skew::Behind(d) => time - d,
+        }
+    }
+}
+
+
+/// A clock corrected for the measured skew, if any.
+struct SkewedClock<'a> {
+    clock: &'a Clock,
+    skew: Option<Skew>,
+}
+
+
+impl<'a> Clock for SkewedClock<'a> {
+    fn now(&self) -> SystemTime {
+        let now = self.clock.now();
+        self.skew.map_or(now, |skew| skew.apply(now))
+    }
+}
+
+
+thread_local! {
+    static DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+}
+
+
+/// Restores the previous deadline on drop, even when unwinding.
+struct DeadlineGuard(Option<Instant>);
+
+
+impl Drop for DeadlineGuard {
+    fn drop(&mut self) {
+        let previous = self.0;
+        DEADLINE.with(|d| d.set(previous));
+    }
+}
+
+
+/// Runs the closure with every request made on the current thread bounded by
+/// the deadline. Nested deadlines can only tighten the bound.
+pub fn with_deadline<F, R>(timeout: Duration, f: F) -> R
+    where F: FnOnce() -> R
+{
+    let deadline = Instant::now() + timeout;
+    let previous = DEADLINE.with(|d| d.get());
+    let deadline = match previous {
+        Some(previous) if previous < deadline => previous,
+        _ => deadline,
+    };
 
-pub struct Open189Client {
-    http: Arc<Client>,
+    let _guard = DeadlineGuard(previous);
+    DEADLINE.with(|d| d.set(Some(deadline)));
+    f()
 }
 
 
 fn prepare_request_params(params: &mut HashMap<&'static str, String>,
                           app_id: &str,
                           secret: &str,
-                          access_token: &str) {
+                          access_token: &str,
+                          clock: &Clock,
+                          middleware: &[Arc<Middleware>]) {
     params.insert("app_id", app_id.to_string());
     params.insert("access_token", access_token.to_string());
-    params.insert("timestamp", util::get_api_timestamp());
-    let sign = sig::sign(params, secret);
+    params.insert("timestamp", util::get_api_timestamp(clock.now()));
+    for layer in middleware {
+        layer.prepare_params(params);
+    }
+    let sign = signature::sign(params, secret);
     params.insert("sign", sign);
 }
 
 
-fn process_response<T>(mut response: Response) -> Result<T::Item>
-    where T: Deserialize + IntoResult
+/// Builds a signed `GET` request with the parameters in the query string.
+pub fn build_get_request<U, S>(app_id: S,
+                               secret: S,
+                               access_token: S,
+                               url: U,
+                               mut params: HashMap<&'static str, String>,
+                               clock: &Clock,
+                               middleware: &[Arc<Middleware>])
+                               -> Result<HttpRequest>
+    where U: IntoUrl,
+          S: AsRef<str>
+{
+    let mut url = url.into_url()?;
+    prepare_request_params(&mut params,
+                           app_id.as_ref(),
+                           secret.as_ref(),
+                           access_token.as_ref(),
+                           clock,
+                           middleware);
+    {
+        let mut qs = url.query_pairs_mut();
+        qs.clear();
+        for (k, v) in &params {
+            qs.append_pair(k.as_ref(), v.as_ref());
+        }
+    }
+
+    Ok(HttpRequest {
+        method: Method::Get,
+        url: url,
+        headers: Headers::new(),
+        body: Vec::new(),
+    })
+}
+
+
+/// Builds a signed `POST` request with the parameters in the form body.
+pub fn build_post_request<U, S>(app_id: S,
+                                secret: S,
+                                access_token: S,
+                                url: U,
+                                mut params: HashMap<&'static str, String>,
+                                clock: &Clock,
+                                middleware: &[Arc<Middleware>])
+                                -> Result<HttpRequest>
+    where U: IntoUrl,
+          S: AsRef<str>
+{
+    prepare_request_params(&mut params,
+                           app_id.as_ref(),
+                           secret.as_ref(),
+                           access_token.as_ref(),
+                           clock,
+                           middleware);
+    build_post_request_prepared(url, params)
+}
+
+
+/// Builds an OAuth access token request.
+pub fn build_access_token_request<U, S>(app_id: S,
+                                        secret: S,
+                                        url: U,
+                                        mut params: HashMap<&'static str, String>,
+                                        middleware: &[Arc<Middleware>])
+                                        -> Result<HttpRequest>
+    where U: IntoUrl,
+          S: AsRef<str>
 {
-    let mut response_str = String::new();
-    response.read_to_string(&mut response_str)?;
-    let obj: T = ::serde_json::from_str(&response_str)?;
+    params.insert("app_id", app_id.as_ref().to_string());
+    params.insert("app_secret", secret.as_ref().to_string());
+    params.insert("state", util::get_random_state_str());
+    for layer in middleware {
+        layer.prepare_params(&mut params);
+    }
+    build_post_request_prepared(url, params)
+}
+
+
+fn build_post_request_prepared<U>(url: U,
+                                  params: HashMap<&'static str, String>)
+                                  -> Result<HttpRequest>
+    where U: IntoUrl
+{
+    let url = url.into_url()?;
+    let body = {
+        let mut serializer = form_urlencoded::Serializer::new(String::new());
+        for (k, v) in &params {
+            serializer.append_pair(k, v);
+        }
+        serializer.finish()
+    };
+
+    let headers = {
+        let mut tmp = Headers::new();
+        tmp.set(ContentType(mime::Mime(mime::TopLevel::Application,
+                                       mime::SubLevel::WwwFormUrlEncoded,
+                                       vec![(mime::Attr::Charset, mime::Value::Utf8)])));
+        tmp
+    };
+
+    Ok(HttpRequest {
+        method: Method::Post,
+        url: url,
+        headers: headers,
+        body: body.into_bytes(),
+    })
+}
+
+
+/// Parses the response body, turning API errors into `Err`s.
+pub fn process_response<R>(response: &HttpResponse) -> Result<R::Item>
+    where R: Deserialize + IntoResult
+{
+    let obj: R = ::serde_json::from_slice(&response.body)?;
     obj.into_result(response.status)
 }
 
 
-impl Open189Client {
-    pub fn new(http_client: Client) -> Open189Client {
-        Open189Client { http: Arc::new(http_client) }
+/// Gets the `res_code` of a call given its error if any: `0` for success, or
+/// the API error code. `None` if the call failed otherwise.
+fn res_code(error: Option<&Error>) -> Option<u64> {
+    match error {
+        Some(e) => e.api_error_code().map(|c| c.code()),
+        None => Some(0),
+    }
+}
+
+
+/// What to do after a failed attempt.
+enum NextStep {
+    /// Re-sign the request with the corrected clock and retry right away.
+    Resign,
+    /// Retry after the delay, as per the retry policy.
+    Backoff(Duration),
+    /// Fail the call.
+    GiveUp,
+}
+
+
+impl<T> Clone for Open189Client<T> {
+    fn clone(&self) -> Open189Client<T> {
+        Open189Client {
+            http: self.http.clone(),
+            retry_policy: self.retry_policy.clone(),
+            clock: self.clock.clone(),
+            user_agent: self.user_agent.clone(),
+            middleware: self.middleware.clone(),
+            hooks: self.hooks.clone(),
+            mask_phone_numbers: self.mask_phone_numbers,
+            metrics: self.metrics.clone(),
+            skew_correction: self.skew_correction,
+            skew: Mutex::new(*self.skew.lock().unwrap()),
+        }
+    }
+}
+
+
+impl<T> Open189Client<T> {
+    pub fn new(transport: T) -> Open189Client<T> {
+        Open189Client {
+            http: Arc::new(transport),
+            retry_policy: Default::default(),
+            clock: Arc::new(SystemClock),
+            user_agent: None,
+            middleware: Vec::new(),
+            hooks: None,
+            mask_phone_numbers: true,
+            metrics: Arc::new(NoopRecorder),
+            skew_correction: false,
+            skew: Mutex::new(None),
+        }
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &*self.clock
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<Clock>) {
+        self.clock = clock;
+        *self.skew.lock().unwrap() = None;
+    }
+
+    pub fn set_skew_correction(&mut self, enabled: bool) {
+        self.skew_correction = enabled;
+        if !enabled {
+            *self.skew.lock().unwrap() = None;
+        }
+    }
+
+    pub fn set_user_agent(&mut self, user_agent: Option<String>) {
+        self.user_agent = user_agent;
+    }
+
+    pub fn middleware(&self) -> &[Arc<Middleware>] {
+        &self.middleware
+    }
+
+    pub fn set_middleware(&mut self, middleware: Vec<Arc<Middleware>>) {
+        self.middleware = middleware;
+    }
+
+    pub fn push_middleware(&mut self, layer: Arc<Middleware>) {
+        self.middleware.push(layer);
+    }
+
+    /// Sets the innermost layer, which stays below any layer pushed later.
+    pub fn set_hooks(&mut self, hooks: Option<Arc<Middleware>>) {
+        self.hooks = hooks;
+    }
+
+    pub fn mask_phone_numbers(&self) -> bool {
+        self.mask_phone_numbers
+    }
+
+    pub fn set_mask_phone_numbers(&mut self, mask: bool) {
+        self.mask_phone_numbers = mask;
+    }
+
+    pub fn set_metrics_recorder(&mut self, metrics: Arc<MetricsRecorder>) {
+        self.metrics = metrics;
+    }
+
+    /// Builds the request for an attempt, timestamped with the clock
+    /// corrected for skew, then decorates it and calls the middleware on it.
+    fn prepare<F>(&self, build_request: &F) -> Result<HttpRequest>
+        where F: Fn(&Clock, &[Arc<Middleware>]) -> Result<HttpRequest>
+    {
+        let clock = SkewedClock {
+            clock: &*self.clock,
+            skew: *self.skew.lock().unwrap(),
+        };
+        let mut request = build_request(&clock, &self.middleware)?;
+
+        if let Some(ref user_agent) = self.user_agent {
+            request.headers.set(UserAgent(user_agent.clone()));
+        }
+        for layer in self.middleware.iter().chain(&self.hooks) {
+            layer.before_send(&mut request);
+        }
+        Ok(request)
+    }
+
+    /// Parses the outcome of sending the request, logging it and calling the
+    /// middleware around it. The HTTP status is returned alongside the result
+    /// if a response is received.
+    fn finish<R>(&self,
+                 request: &HttpRequest,
+                 result: Result<HttpResponse>,
+                 endpoint: &str,
+                 attempt: u32,
+                 latency: Duration)
+                 -> (Option<StatusCode>, Result<R::Item>)
+        where R: Deserialize + IntoResult
+    {
+        let (response, parsed) = match result {
+            Ok(response) => {
+                if self.skew_correction {
+                    self.measure_skew(&response.headers);
+                }
+                let parsed = process_response::<R>(&response);
+                (Some(response), parsed)
+            }
+            Err(e) => (None, Err(e)),
+        };
+
+        let millis = util::duration_millis(latency);
+        match (&response, &parsed) {
+            (_, &Ok(_)) => {
+                debug!("{} attempt {}: succeeded in {} ms", endpoint, attempt, millis);
+            }
+            (&Some(ref response), &Err(ref e)) => {
+                warn!("{} attempt {}: HTTP {} in {} ms, res_code {}: {}",
+                      endpoint,
+                      attempt,
+                      response.status,
+                      millis,
+                      e.api_error_code().map_or("-".to_string(), |c| c.code().to_string()),
+                      e);
+            }
+            (&None, &Err(ref e)) => {
+                warn!("{} attempt {}: failed after {} ms: {}", endpoint, attempt, millis, e);
+            }
+        }
+
+        if !self.middleware.is_empty() || self.hooks.is_some() {
+            let exchange = Exchange {
+                request: request,
+                response: response.as_ref(),
+                latency: latency,
+                res_code: res_code(parsed.as_ref().err()),
+                error: parsed.as_ref().err(),
+            };
+            for layer in self.hooks.iter().chain(self.middleware.iter().rev()) {
+                layer.after_response(&exchange);
+            }
+        }
+        (response.map(|response| response.status), parsed)
+    }
+
+    /// Decides whether to retry after the attempt failed with `e`.
+    ///
+    /// With skew correction enabled, a request rejected for its timestamp is
+    /// re-signed and retried once right away, regardless of the policy; the
+    /// skew is measured from the rejection itself.
+    fn next_step(&self,
+                 e: &Error,
+                 endpoint: &str,
+                 attempt: u32,
+                 idempotent: bool,
+                 timestamp_retried: bool)
+                 -> NextStep {
+        if self.skew_correction && !timestamp_retried &&
+           e.api_error_code() == Some(ApiErrorCode::TimestampExpired) {
+            info!("{}: timestamp rejected, retrying with corrected clock", endpoint);
+            return NextStep::Resign;
+        }
+        if !self.retry_policy.should_retry(e, attempt, idempotent) {
+            return NextStep::GiveUp;
+        }
+        NextStep::Backoff(self.retry_policy.delay(attempt))
+    }
+
+    /// Reports the call as a whole to the metrics recorder.
+    fn record(&self,
+              endpoint: &str,
+              started: Instant,
+              status: Option<StatusCode>,
+              error: Option<&Error>,
+              retries: u32) {
+        self.metrics.record(&CallMetrics {
+            endpoint: endpoint,
+            status: status,
+            res_code: res_code(error),
+            duration: started.elapsed(),
+            retries: retries,
+        });
     }
 
-    pub fn get_sync<U, S, T>(&self,
+    /// Updates the skew from the `Date` header of the response, if present.
+    fn measure_skew(&self, headers: &Headers) {
+        let server_time = headers.get_raw("Date")
+            .and_then(|values| values.first())
+            .and_then(|value| ::std::str::from_utf8(value).ok())
+            .and_then(util::parse_http_date);
+        if let Some(server_time) = server_time {
+            let skew = Skew::between(server_time, self.clock.now());
+            debug!("server clock skew: {:?}", skew);
+            *self.skew.lock().unwrap() = Some(skew);
+        }
+    }
+}
+
+
+impl<T: Transport> Open189Client<T> {
+    pub fn get_sync<U, S, R>(&self,
                              app_id: S,
                              secret: S,
                              access_token: S,
+                             endpoint: &str,
                              url: U,
-                             mut params: HashMap<&'static str, String>)
-                             -> Result<T::Item>
+                             params: HashMap<&'static str, String>)
+                             -> Result<R::Item>
+        where U: IntoUrl,
+              S: AsRef<str>,
+              R: Deserialize + IntoResult
+    {
+        let url = url.into_url()?;
+        debug!("GET {} {}",
+               url.path(),
+               redact::Params::new(&params, self.mask_phone_numbers));
+        self.perform::<R, _>(endpoint, true, |clock, middleware| {
+            build_get_request(app_id.as_ref(),
+                              secret.as_ref(),
+                              access_token.as_ref(),
+                              url.clone(),
+                              params.clone(),
+                              clock,
+                              middleware)
+        })
+    }
+
+    /// Performs a signed `POST` request.
+    ///
+    /// These requests send SMS, and are thus treated as non-idempotent.
+    pub fn post_sync<U, S, R>(&self,
+                              app_id: S,
+                              secret: S,
+                              access_token: S,
+                              endpoint: &str,
+                              url: U,
+                              params: HashMap<&'static str, String>)
+                              -> Result<R::Item>
         where U: IntoUrl,
               S: AsRef<str>,
-              T: Deserialize + IntoResult
+              R: Deserialize + IntoResult
     {
-        let mut url = url.into_url()?;
-        prepare_request_params(&mut params,
-                               app_id.as_ref(),
+        let url = url.into_url()?;
+        debug!("POST {} {}",
+               url.path(),
+               redact::Params::new(&params, self.mask_phone_numbers));
+        self.perform::<R, _>(endpoint, false, |clock, middleware| {
+            build_post_request(app_id.as_ref(),
                                secret.as_ref(),
-                               access_token.as_ref());
-        {
-            let mut qs = url.query_pairs_mut();
-            qs.clear();
-            for (k, v) in &params {
-                qs.append_pair(k.as_ref(), v.as_ref());
+                               access_token.as_ref(),
+                               url.clone(),
+                               params.clone(),
+                               clock,
+                               middleware)
+        })
+    }
+
+    pub fn perform_access_token_req<U, S, R>(&self,
+                                             app_id: S,
+                                             secret: S,
+                                             endpoint: &str,
+                                             url: U,
+                                             params: HashMap<&'static str, String>)
+                                             -> Result<R::Item>
+        where U: IntoUrl,
+              S: AsRef<str>,
+              R: Deserialize + IntoResult
+    {
+        let url = url.into_url()?;
+        debug!("POST {} {}",
+               url.path(),
+               redact::Params::new(&params, self.mask_phone_numbers));
+        self.perform::<R, _>(endpoint, true, |_, middleware| {
+            build_access_token_request(app_id.as_ref(),
+                                       secret.as_ref(),
+                                       url.clone(),
+                                       params.clone(),
+                                       middleware)
+        })
+    }
+
+    /// Performs the request according to the retry policy. The request is
+    /// built anew for every attempt, so that it's freshly timestamped and
+    /// signed.
+    ///
+    /// The call as a whole is reported to the metrics recorder. `endpoint`
+    /// names the endpoint called in logs and metrics; it's the path of the
+    /// endpoint relative to the base URL, so it stays the same whatever the
+    /// base.
+    fn perform<R, F>(&self, endpoint: &str, idempotent: bool, build_request: F) -> Result<R::Item>
+        where R: Deserialize + IntoResult,
+              F: Fn(&Clock, &[Arc<Middleware>]) -> Result<HttpRequest>
+    {
+        let deadline = DEADLINE.with(|d| d.get());
+        let started = Instant::now();
+
+        let mut attempt = 1;
+        let mut retries = 0;
+        let mut timestamp_retried = false;
+        loop {
+            let (status, result) = match self.prepare(&build_request) {
+                Ok(request) => {
+                    let sent_at = Instant::now();
+                    let response = self.send(request.clone(), deadline);
+                    self.finish::<R>(&request, response, endpoint, attempt, sent_at.elapsed())
+                }
+                Err(e) => (None, Err(e)),
+            };
+
+            let e = match result {
+                Ok(item) => {
+                    self.record(endpoint, started, status, None, retries);
+                    return Ok(item);
+                }
+                Err(e) => e,
+            };
+            let next = self.next_step(&e, endpoint, attempt, idempotent, timestamp_retried);
+            let delay = match next {
+                NextStep::Resign => {
+                    timestamp_retried = true;
+                    retries += 1;
+                    continue;
+                }
+                NextStep::Backoff(delay) => delay,
+                NextStep::GiveUp => {
+                    self.record(endpoint, started, status, Some(&e), retries);
+                    return Err(e);
+                }
+            };
+            if deadline.map_or(false, |deadline| Instant::now() + delay >= deadline) {
+                self.record(endpoint, started, status, Some(&e), retries);
+                return Err(e);
             }
+
+            info!("{}: retrying in {} ms", endpoint, util::duration_millis(delay));
+
+            thread::sleep(delay);
+            attempt += 1;
+            retries += 1;
         }
+    }
+
+    /// Sends the request, bounding it by the time left until the deadline if
+    /// there's one.
+    ///
+    /// The transport enforces the bound on the socket, so a timeout may still
+    /// leave the request delivered; non-idempotent requests are thus never
+    /// retried after one.
+    fn send(&self, request: HttpRequest, deadline: Option<Instant>) -> Result<HttpResponse> {
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return self.http.send(request),
+        };
 
-        let response = self.http.get(url).send()?;
-        process_response::<T>(response)
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(ErrorKind::Timeout(TimeoutKind::Deadline).into());
+        }
+
+        let result = self.http.send_timeout(request, deadline - now);
+        if let Err(ref e) = result {
+            if let ErrorKind::Timeout(_) = *e.kind() {
+                if Instant::now() >= deadline {
+                    return Err(ErrorKind::Timeout(TimeoutKind::Deadline).into());
+                }
+            }
+        }
+        result
     }
+}
 
-    pub fn post_sync<U, S, T>(&self,
+
+/// The asynchronous counterparts of the request methods. They're associated
+/// functions taking the client behind an `Arc`, as the returned futures keep
+/// it alive until they complete.
+impl<T: AsyncTransport> Open189Client<T> {
+    pub fn get_async<U, S, R>(client: &Arc<Open189Client<T>>,
                               app_id: S,
                               secret: S,
                               access_token: S,
+                              endpoint: &'static str,
                               url: U,
-                              mut params: HashMap<&'static str, String>)
-                              -> Result<T::Item>
+                              params: HashMap<&'static str, String>)
+                              -> BoxFuture<R::Item, Error>
         where U: IntoUrl,
               S: AsRef<str>,
-              T: Deserialize + IntoResult
+              R: Deserialize + IntoResult + 'static,
+              R::Item: Send + 'static
     {
-        prepare_request_params(&mut params,
-                               app_id.as_ref(),
-                               secret.as_ref(),
-                               access_token.as_ref());
-        self.post_sync_prepared::<U, T>(url, params)
+        let url = match url.into_url() {
+            Ok(url) => url,
+            Err(e) => return future::err(Error::from(e)).boxed(),
+        };
+        debug!("GET {} {}",
+               url.path(),
+               redact::Params::new(&params, client.mask_phone_numbers));
+        let app_id = app_id.as_ref().to_string();
+        let secret = secret.as_ref().to_string();
+        let access_token = access_token.as_ref().to_string();
+        Open189Client::perform_async::<R, _>(client, endpoint, true, move |clock, middleware| {
+            build_get_request(&app_id[..],
+                              &secret[..],
+                              &access_token[..],
+                              url.clone(),
+                              params.clone(),
+                              clock,
+                              middleware)
+        })
     }
 
-    pub fn perform_access_token_req<S, T>(&self,
-                                          app_id: S,
-                                          secret: S,
-                                          mut params: HashMap<&'static str, String>)
-                                          -> Result<T::Item>
-        where S: AsRef<str>,
-              T: Deserialize + IntoResult
+    /// Performs a signed `POST` request, treated as non-idempotent.
+    pub fn post_async<U, S, R>(client: &Arc<Open189Client<T>>,
+                               app_id: S,
+                               secret: S,
+                               access_token: S,
+                               endpoint: &'static str,
+                               url: U,
+                               params: HashMap<&'static str, String>)
+                               -> BoxFuture<R::Item, Error>
+        where U: IntoUrl,
+              S: AsRef<str>,
+              R: Deserialize + IntoResult + 'static,
+              R::Item: Send + 'static
     {
-        params.insert("app_id", app_id.as_ref().to_string());
-        params.insert("app_secret", secret.as_ref().to_string());
-        params.insert("state", util::get_random_state_str());
-        self.post_sync_prepared::<_, T>(URL_ACCESS_TOKEN_REQ, params)
+        let url = match url.into_url() {
+            Ok(url) => url,
+            Err(e) => return future::err(Error::from(e)).boxed(),
+        };
+        debug!("POST {} {}",
+               url.path(),
+               redact::Params::new(&params, client.mask_phone_numbers));
+        let app_id = app_id.as_ref().to_string();
+        let secret = secret.as_ref().to_string();
+        let access_token = access_token.as_ref().to_string();
+        Open189Client::perform_async::<R, _>(client, endpoint, false, move |clock, middleware| {
+            build_post_request(&app_id[..],
+                               &secret[..],
+                               &access_token[..],
+                               url.clone(),
+                               params.clone(),
+                               clock,
+                               middleware)
+        })
     }
 
-    fn post_sync_prepared<U, T>(&self,
-                                url: U,
-                                params: HashMap<&'static str, String>)
-                                -> Result<T::Item>
+    pub fn access_token_req_async<U, S, R>(client: &Arc<Open189Client<T>>,
+                                           app_id: S,
+                                           secret: S,
+                                           endpoint: &'static str,
+                                           url: U,
+                                           params: HashMap<&'static str, String>)
+                                           -> BoxFuture<R::Item, Error>
         where U: IntoUrl,
-              T: Deserialize + IntoResult
+              S: AsRef<str>,
+              R: Deserialize + IntoResult + 'static,
+              R::Item: Send + 'static
     {
-        let url = url.into_url()?;
-        let body = {
-            let mut serializer = form_urlencoded::Serializer::new(String::new());
-            for (k, v) in &params {
-                serializer.append_pair(k, v);
-            }
-            serializer.finish()
+        let url = match url.into_url() {
+            Ok(url) => url,
+            Err(e) => return future::err(Error::from(e)).boxed(),
         };
+        debug!("POST {} {}",
+               url.path(),
+               redact::Params::new(&params, client.mask_phone_numbers));
+        let app_id = app_id.as_ref().to_string();
+        let secret = secret.as_ref().to_string();
+        Open189Client::perform_async::<R, _>(client, endpoint, true, move |_, middleware| {
+            build_access_token_request(&app_id[..],
+                                       &secret[..],
+                                       url.clone(),
+                                       params.clone(),
+                                       middleware)
+        })
+    }
 
-        let headers = {
-            let mut tmp = Headers::new();
-            tmp.set(ContentType(mime::Mime(mime::TopLevel::Application,
-                                           mime::SubLevel::WwwFormUrlEncoded,
-                                           vec![(mime::Attr::Charset, mime::Value::Utf8)])));
-            tmp
-        };
+    /// Performs the request like `perform`, with the attempts chained as
+    /// futures and the delays between them waited for by the transport.
+    ///
+    /// Deadlines set with `with_deadline` only apply to the thread they're
+    /// set on, so they don't bound asynchronous calls.
+    fn perform_async<R, F>(client: &Arc<Open189Client<T>>,
+                           endpoint: &'static str,
+                           idempotent: bool,
+                           build_request: F)
+                           -> BoxFuture<R::Item, Error>
+        where R: Deserialize + IntoResult + 'static,
+              R::Item: Send + 'static,
+              F: Fn(&Clock, &[Arc<Middleware>]) -> Result<HttpRequest> + Send + 'static
+    {
+        let client = client.clone();
+        let started = Instant::now();
+
+        // the state is the attempt number, the number of retries so far, and
+        // whether the request has been re-signed after a timestamp rejection
+        let attempts = future::loop_fn((1, 0, false), move |state| {
+            let (attempt, retries, timestamp_retried) = state;
+            let request = match client.prepare(&build_request) {
+                Ok(request) => request,
+                Err(e) => {
+                    client.record(endpoint, started, None, Some(&e), retries);
+                    return future::err(e).boxed();
+                }
+            };
+
+            let client = client.clone();
+            let sent_at = Instant::now();
+            let sending = client.http.send_async(request.clone());
+            sending.then(move |response| {
+                let (status, result) = client.finish::<R>(&request,
+                                                          response,
+                                                          endpoint,
+                                                          attempt,
+                                                          sent_at.elapsed());
+                let e = match result {
+                    Ok(item) => {
+                        client.record(endpoint, started, status, None, retries);
+                        return future::ok(Loop::Break(item)).boxed();
+                    }
+                    Err(e) => e,
+                };
+                match client.next_step(&e, endpoint, attempt, idempotent, timestamp_retried) {
+                    NextStep::Resign => {
+                        future::ok(Loop::Continue((attempt, retries + 1, true))).boxed()
+                    }
+                    NextStep::Backoff(delay) => {
+                        info!("{}: retrying in {} ms", endpoint, util::duration_millis(delay));
+                        client.http
+                            .delay(delay)
+                            .map(move |_| {
+                                Loop::Continue((attempt + 1, retries + 1, timestamp_retried))
+                            })
+                            .boxed()
+                    }
+                    NextStep::GiveUp => {
+                        client.record(endpoint, started, status, Some(&e), retries);
+                        future::err(e).boxed()
+                    }
+                }
+            }).boxed()
+        });
+        attempts.boxed()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+
+    #[test]
+    fn test_skew() {
+        let local = UNIX_EPOCH + Duration::from_secs(1000);
+        let server = UNIX_EPOCH + Duration::from_secs(1600);
+
+        let skew = Skew::between(server, local);
+        assert_eq!(skew, Skew::Ahead(Duration::from_secs(600)));
+        assert_eq!(skew.apply(local), server);
 
-        let response = self.http.post(url).headers(headers).body(&body).send()?;
-        process_response::<T>(response)
+        let skew = Skew::between(local, server);
+        assert_eq!(skew, Skew::Behind(Duration::from_secs(600)));
+        assert_eq!(skew.apply(server), local);
     }
 }
@@ -0,0 +1,127 @@
+//! Helpers for the user-authorized OAuth 2.0 flow.
+//!
+//! To act on behalf of a user, first redirect the user to the authorization
+//! URL built with [`AuthorizeUrlBuilder`]. After the user grants access, the
+//! API redirects back to your `redirect_uri` with a `code` query parameter,
+//! which is then exchanged for a user access token with
+//! [`Open189App::get_access_token_ac`].
+//!
+//! [`AuthorizeUrlBuilder`]: struct.AuthorizeUrlBuilder.html
+//! [`Open189App::get_access_token_ac`]: ../struct.Open189App.html#method.get_access_token_ac
+
+use url::Url;
+
+use super::endpoints::Endpoints;
+use super::errors::*;
+use super::req;
+
+
+/// Builder for the user authorization URL.
+///
+/// # Examples
+///
+/// ```
+/// use open189::oauth::AuthorizeUrlBuilder;
+///
+/// let url = AuthorizeUrlBuilder::new("your app id here", "https://example.com/oauth/callback")
+///     .state("some random nonce")
+///     .scope("user_info")
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct AuthorizeUrlBuilder {
+    endpoints: Endpoints,
+    app_id: String,
+    redirect_uri: String,
+    state: Option<String>,
+    scope: Option<String>,
+}
+
+
+impl AuthorizeUrlBuilder {
+    /// Start building the authorization URL for the given app ID and redirect
+    /// URI.
+    pub fn new<S: AsRef<str>>(app_id: S, redirect_uri: S) -> AuthorizeUrlBuilder {
+        AuthorizeUrlBuilder {
+            endpoints: Endpoints::default(),
+            app_id: app_id.as_ref().to_string(),
+            redirect_uri: redirect_uri.as_ref().to_string(),
+            state: None,
+            scope: None,
+        }
+    }
+
+    /// Set the endpoints to build the URL against, instead of the production
+    /// ones.
+    pub fn endpoints(mut self, endpoints: &Endpoints) -> AuthorizeUrlBuilder {
+        self.endpoints = endpoints.clone();
+        self
+    }
+
+    /// Set the opaque state value, which is passed back unchanged to the
+    /// redirect URI.
+    ///
+    /// You should always set this to an unguessable value tied to the user's
+    /// session, and check it on redirect, to protect against CSRF attacks.
+    pub fn state<S: AsRef<str>>(mut self, state: S) -> AuthorizeUrlBuilder {
+        self.state = Some(state.as_ref().to_string());
+        self
+    }
+
+    /// Set the requested scope.
+    pub fn scope<S: AsRef<str>>(mut self, scope: S) -> AuthorizeUrlBuilder {
+        self.scope = Some(scope.as_ref().to_string());
+        self
+    }
+
+    /// Build the authorization URL.
+    ///
+    /// The redirect URI is validated here, and an `Err` is returned if it's
+    /// not a valid URL.
+    pub fn build(self) -> Result<Url> {
+        let redirect_uri = Url::parse(&self.redirect_uri)?;
+
+        let mut url = self.endpoints.oauth_url(req::PATH_AUTHORIZE)?;
+        {
+            let mut qs = url.query_pairs_mut();
+            qs.append_pair("app_id", &self.app_id);
+            qs.append_pair("redirect_uri", redirect_uri.as_str());
+            qs.append_pair("response_type", "code");
+            if let Some(ref state) = self.state {
+                qs.append_pair("state", state);
+            }
+            if let Some(ref scope) = self.scope {
+                qs.append_pair("scope", scope);
+            }
+        }
+
+        Ok(url)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_authorize_url() {
+        let url = AuthorizeUrlBuilder::new("123", "https://example.com/cb?a=1")
+            .state("xyz")
+            .build()
+            .unwrap();
+        assert_eq!(url.as_str(),
+                   "https://oauth.api.189.cn/emp/oauth2/v3/authorize?app_id=123&\
+                    redirect_uri=https%3A%2F%2Fexample.com%2Fcb%3Fa%3D1&response_type=code&\
+                    state=xyz");
+
+        let endpoints = Endpoints::new("http://127.0.0.1:8080/", "http://127.0.0.1:8081/").unwrap();
+        let url = AuthorizeUrlBuilder::new("123", "https://example.com/cb")
+            .endpoints(&endpoints)
+            .build()
+            .unwrap();
+        assert!(url.as_str().starts_with("http://127.0.0.1:8080/emp/oauth2/v3/authorize?"));
+    }
+}
@@ -0,0 +1,176 @@
+//! Mainland China mobile phone numbers.
+//!
+//! The API only delivers SMS to Mainland China mobile numbers, written as 11
+//! bare digits. [`PhoneNumber`] normalizes numbers as commonly entered by
+//! users, and validates them locally so that typos don't waste SMS tokens.
+//!
+//! [`PhoneNumber`]: struct.PhoneNumber.html
+
+use std::fmt;
+
+use super::errors::*;
+
+/// Length of a normalized phone number.
+const PHONE_NUMBER_LENGTH: usize = 11;
+
+
+/// The carrier a phone number segment is allocated to.
+///
+/// Numbers ported to another carrier keep their original segment, so this
+/// is only a hint.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Carrier {
+    /// China Telecom, the carrier behind `open.189.cn`.
+    ChinaTelecom,
+    /// China Mobile.
+    ChinaMobile,
+    /// China Unicom.
+    ChinaUnicom,
+    /// China Broadnet.
+    ChinaBroadnet,
+    /// The segment isn't known to be allocated.
+    Unknown,
+}
+
+
+/// A validated Mainland China mobile phone number.
+///
+/// # Examples
+///
+/// ```
+/// use open189::phone::{Carrier, PhoneNumber};
+///
+/// let phone = PhoneNumber::parse("+86 189-1234-5678").unwrap();
+/// assert_eq!(phone.as_str(), "18912345678");
+/// assert_eq!(phone.carrier(), Carrier::ChinaTelecom);
+///
+/// assert!(PhoneNumber::parse("1891234567").is_err());
+/// ```
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct PhoneNumber(String);
+
+
+impl PhoneNumber {
+    /// Parse and validate a phone number.
+    ///
+    /// Spaces and dashes are ignored, as is the `+86` or `0086` country
+    /// code. The number must then consist of 11 digits, starting with `13`
+    /// through `19`; otherwise an `ErrorKind::InvalidPhoneNumber` error is
+    /// returned.
+    pub fn parse<S: AsRef<str>>(phone: S) -> Result<PhoneNumber> {
+        let input = phone.as_ref();
+        let stripped: String = input.chars().filter(|&ch| ch != ' ' && ch != '-').collect();
+
+        let number = if stripped.starts_with("+86") {
+            &stripped[3..]
+        } else if stripped.starts_with("0086") {
+            &stripped[4..]
+        } else {
+            &stripped[..]
+        };
+
+        let bytes = number.as_bytes();
+        let valid = bytes.len() == PHONE_NUMBER_LENGTH &&
+                    bytes.iter().all(|b| b.is_ascii_digit()) &&
+                    bytes[0] == b'1' && bytes[1] >= b'3';
+        if !valid {
+            return Err(ErrorKind::InvalidPhoneNumber(input.to_string()).into());
+        }
+
+        Ok(PhoneNumber(number.to_string()))
+    }
+
+    /// Get the normalized number, i.e. the 11 bare digits.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Convert into the normalized number.
+    pub fn into_string(self) -> String {
+        self.0
+    }
+
+    /// Identify the carrier the number's segment is allocated to.
+    pub fn carrier(&self) -> Carrier {
+        match &self.0[..3] {
+            "133" | "149" | "153" | "162" | "173" | "174" | "177" | "180" | "181" | "189" |
+            "190" | "191" | "193" | "199" => Carrier::ChinaTelecom,
+            "135" | "136" | "137" | "138" | "139" | "147" | "148" | "150" | "151" | "152" |
+            "157" | "158" | "159" | "165" | "172" | "178" | "182" | "183" | "184" | "187" |
+            "188" | "195" | "197" | "198" => Carrier::ChinaMobile,
+            "130" | "131" | "132" | "145" | "146" | "155" | "156" | "166" | "167" | "171" |
+            "175" | "176" | "185" | "186" | "196" => Carrier::ChinaUnicom,
+            "192" => Carrier::ChinaBroadnet,
+            // 1349 is a China Telecom satellite segment
+            "134" if &self.0[3..4] == "9" => Carrier::ChinaTelecom,
+            "134" => Carrier::ChinaMobile,
+            // virtual operators, split among the carriers
+            "170" => {
+                match &self.0[3..4] {
+                    "0" | "1" | "2" => Carrier::ChinaTelecom,
+                    "3" | "5" | "6" => Carrier::ChinaMobile,
+                    "4" | "7" | "8" | "9" => Carrier::ChinaUnicom,
+                    _ => Carrier::Unknown,
+                }
+            }
+            _ => Carrier::Unknown,
+        }
+    }
+
+    /// Whether the number's segment is allocated to China Telecom.
+    pub fn is_china_telecom(&self) -> bool {
+        self.carrier() == Carrier::ChinaTelecom
+    }
+}
+
+
+impl fmt::Display for PhoneNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+
+impl AsRef<str> for PhoneNumber {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_parse() {
+        for input in &["18912345678", "+8618912345678", "008618912345678", "189 1234 5678",
+                       "+86 189-1234-5678"] {
+            assert_eq!(PhoneNumber::parse(input).unwrap().as_str(), "18912345678");
+        }
+
+        for input in &["", "1891234567", "189123456789", "12345678901", "28912345678",
+                       "1891234567a", "86 18912345678"] {
+            match *PhoneNumber::parse(input).unwrap_err().kind() {
+                ErrorKind::InvalidPhoneNumber(ref phone) => assert_eq!(phone, *input),
+                ref e => panic!("unexpected error {:?}", e),
+            }
+        }
+    }
+
+
+    #[test]
+    fn test_carrier() {
+        let carrier = |phone: &str| PhoneNumber::parse(phone).unwrap().carrier();
+        assert_eq!(carrier("18912345678"), Carrier::ChinaTelecom);
+        assert_eq!(carrier("13492345678"), Carrier::ChinaTelecom);
+        assert_eq!(carrier("17012345678"), Carrier::ChinaTelecom);
+        assert_eq!(carrier("13812345678"), Carrier::ChinaMobile);
+        assert_eq!(carrier("13412345678"), Carrier::ChinaMobile);
+        assert_eq!(carrier("18612345678"), Carrier::ChinaUnicom);
+        assert_eq!(carrier("19212345678"), Carrier::ChinaBroadnet);
+        assert_eq!(carrier("14012345678"), Carrier::Unknown);
+        assert!(PhoneNumber::parse("13312345678").unwrap().is_china_telecom());
+    }
+}
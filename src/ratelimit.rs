@@ -0,0 +1,227 @@
+//! Local rate limiting of SMS sending.
+//!
+//! The API throttles sending per phone number and per app, but only tells you
+//! so with opaque error codes after the fact. A [`RateLimiter`] installed
+//! with [`Open189App::set_rate_limiter`] enforces your own quotas locally
+//! instead, rejecting sends with [`ErrorKind::RateLimited`] before any request
+//! is made.
+//!
+//! [`RateLimiter`]: struct.RateLimiter.html
+//! [`Open189App::set_rate_limiter`]: ../struct.Open189App.html#method.set_rate_limiter
+//! [`ErrorKind::RateLimited`]: ../errors/enum.ErrorKind.html#variant.RateLimited
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::errors::*;
+
+
+/// A maximum number of sends allowed within a sliding time window.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Quota {
+    /// The number of sends allowed.
+    pub count: usize,
+    /// The length of the window.
+    pub period: Duration,
+}
+
+
+impl Quota {
+    /// Construct a quota of `count` sends per `period`.
+    pub fn new(count: usize, period: Duration) -> Quota {
+        Quota {
+            count: count,
+            period: period,
+        }
+    }
+
+    /// Construct a quota of `count` sends per second.
+    pub fn per_second(count: usize) -> Quota {
+        Quota::new(count, Duration::from_secs(1))
+    }
+
+    /// Construct a quota of `count` sends per minute.
+    pub fn per_minute(count: usize) -> Quota {
+        Quota::new(count, Duration::from_secs(60))
+    }
+
+    /// Construct a quota of `count` sends per hour.
+    pub fn per_hour(count: usize) -> Quota {
+        Quota::new(count, Duration::from_secs(3600))
+    }
+
+    /// Construct a quota of `count` sends per day.
+    pub fn per_day(count: usize) -> Quota {
+        Quota::new(count, Duration::from_secs(86400))
+    }
+
+    /// How long to wait until another send fits in the quota, given the
+    /// past sends in ascending order; `None` if one is allowed right now.
+    fn retry_after(&self, log: &VecDeque<Instant>, now: Instant) -> Option<Duration> {
+        let in_window = log.iter().rev().take_while(|&&t| now.duration_since(t) < self.period).count();
+        if in_window < self.count {
+            return None;
+        }
+
+        if self.count == 0 {
+            return Some(self.period);
+        }
+
+        // the send that has to fall out of the window first
+        let oldest = log[log.len() - self.count];
+        Some(self.period - now.duration_since(oldest))
+    }
+}
+
+
+/// Sliding-window log of past sends.
+#[derive(Debug, Default)]
+struct SendLog {
+    phones: HashMap<String, VecDeque<Instant>>,
+    app: VecDeque<Instant>,
+    phones_len_after_gc: usize,
+}
+
+
+/// Per-phone and per-app rate limiter for SMS sending.
+///
+/// Every quota is enforced over a sliding window. The state is kept in
+/// memory, so the limits apply per process.
+///
+/// # Examples
+///
+/// ```
+/// use open189::Open189App;
+/// use open189::ratelimit::{Quota, RateLimiter};
+///
+/// let limiter = RateLimiter::new(vec![Quota::per_minute(1), Quota::per_day(10)],
+///                                vec![Quota::per_second(20)]);
+///
+/// let mut client = Open189App::new("your app id here", "your app secret here");
+/// client.set_rate_limiter(Some(limiter));
+/// ```
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_phone: Vec<Quota>,
+    per_app: Vec<Quota>,
+    log: Mutex<SendLog>,
+}
+
+
+impl RateLimiter {
+    /// Construct a rate limiter enforcing the given per-phone and per-app
+    /// quotas.
+    pub fn new(per_phone: Vec<Quota>, per_app: Vec<Quota>) -> RateLimiter {
+        RateLimiter {
+            per_phone: per_phone,
+            per_app: per_app,
+            log: Mutex::new(Default::default()),
+        }
+    }
+
+    /// Get the per-phone quotas.
+    pub fn per_phone(&self) -> &[Quota] {
+        &self.per_phone
+    }
+
+    /// Get the per-app quotas.
+    pub fn per_app(&self) -> &[Quota] {
+        &self.per_app
+    }
+
+    /// Check whether a send to the phone number is allowed right now, and
+    /// record it if so.
+    ///
+    /// Returns an `ErrorKind::RateLimited` error carrying the time to wait
+    /// if any of the quotas is exceeded.
+    pub fn check(&self, phone: &str) -> Result<()> {
+        let now = Instant::now();
+        let mut log = self.log.lock().unwrap();
+        let log = &mut *log;
+
+        let max_phone_period = max_period(&self.per_phone);
+        let max_app_period = max_period(&self.per_app);
+        prune(&mut log.app, now, max_app_period);
+
+        let retry_after = {
+            let empty = VecDeque::new();
+            let phone_log = match log.phones.get_mut(phone) {
+                Some(phone_log) => {
+                    prune(phone_log, now, max_phone_period);
+                    &*phone_log
+                }
+                None => &empty,
+            };
+
+            let phone_waits = self.per_phone.iter().filter_map(|q| q.retry_after(phone_log, now));
+            let app_waits = self.per_app.iter().filter_map(|q| q.retry_after(&log.app, now));
+            phone_waits.chain(app_waits).max()
+        };
+        if let Some(retry_after) = retry_after {
+            return Err(ErrorKind::RateLimited(retry_after).into());
+        }
+
+        if !self.per_app.is_empty() {
+            log.app.push_back(now);
+        }
+        if !self.per_phone.is_empty() {
+            log.phones.entry(phone.to_string()).or_insert_with(VecDeque::new).push_back(now);
+
+            // forget about idle phone numbers once in a while
+            if log.phones.len() > 2 * log.phones_len_after_gc {
+                for phone_log in log.phones.values_mut() {
+                    prune(phone_log, now, max_phone_period);
+                }
+                log.phones.retain(|_, phone_log| !phone_log.is_empty());
+                log.phones_len_after_gc = log.phones.len();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+fn max_period(quotas: &[Quota]) -> Duration {
+    quotas.iter().map(|q| q.period).max().unwrap_or(Duration::from_secs(0))
+}
+
+
+/// Drops sends that are too old to matter for any quota.
+fn prune(log: &mut VecDeque<Instant>, now: Instant, max_period: Duration) {
+    while log.front().map_or(false, |&t| now.duration_since(t) >= max_period) {
+        log.pop_front();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_per_phone_quota() {
+        let limiter = RateLimiter::new(vec![Quota::per_minute(2)], vec![]);
+        assert!(limiter.check("12345678901").is_ok());
+        assert!(limiter.check("12345678901").is_ok());
+        assert!(limiter.check("12345678902").is_ok());
+
+        match *limiter.check("12345678901").unwrap_err().kind() {
+            ErrorKind::RateLimited(retry_after) => {
+                assert!(retry_after <= Duration::from_secs(60));
+            }
+            ref e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+
+    #[test]
+    fn test_per_app_quota() {
+        let limiter = RateLimiter::new(vec![], vec![Quota::per_minute(2)]);
+        assert!(limiter.check("12345678901").is_ok());
+        assert!(limiter.check("12345678902").is_ok());
+        assert!(limiter.check("12345678903").is_err());
+    }
+}
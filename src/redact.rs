@@ -0,0 +1,114 @@
+//! Redaction of sensitive values in log messages.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::iter;
+
+/// Parameters whose values are safe to log as is. The values of any other
+/// parameter, including ones added by middleware, are never written.
+const PLAIN_PARAMS: &'static [&'static str] = &["app_id",
+                                                "exp_time",
+                                                "grant_type",
+                                                "redirect_uri",
+                                                "state",
+                                                "template_id",
+                                                "timestamp",
+                                                "url"];
+/// Parameters carrying phone numbers.
+const PHONE_PARAMS: &'static [&'static str] = &["acceptor_tel", "phone"];
+/// Placeholder for redacted values.
+pub const REDACTED: &'static str = "<redacted>";
+
+
+/// Masks the middle digits of the phone number, e.g. `138****1234`, unless
+/// masking is disabled.
+pub fn phone(phone: &str, mask: bool) -> Cow<str> {
+    if !mask {
+        return Cow::Borrowed(phone);
+    }
+
+    let chars: Vec<_> = phone.chars().collect();
+    if chars.len() < 8 {
+        return Cow::Owned(iter::repeat('*').take(chars.len()).collect());
+    }
+
+    let mut result: String = chars[..3].iter().cloned().collect();
+    result.extend(iter::repeat('*').take(chars.len() - 7));
+    result.extend(chars[chars.len() - 4..].iter().cloned());
+    Cow::Owned(result)
+}
+
+
+/// Request parameters, formatted for logging with sensitive values redacted.
+pub struct Params<'a> {
+    params: &'a HashMap<&'static str, String>,
+    mask_phones: bool,
+}
+
+
+impl<'a> Params<'a> {
+    pub fn new(params: &'a HashMap<&'static str, String>, mask_phones: bool) -> Params<'a> {
+        Params {
+            params: params,
+            mask_phones: mask_phones,
+        }
+    }
+}
+
+
+impl<'a> fmt::Display for Params<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut keys: Vec<_> = self.params.keys().collect();
+        keys.sort();
+        for (i, &k) in keys.into_iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            let v = &self.params[k];
+            if PLAIN_PARAMS.contains(&k) {
+                write!(f, "{}={}", k, v)?;
+            } else if PHONE_PARAMS.contains(&k) {
+                write!(f, "{}={}", k, phone(v, self.mask_phones))?;
+            } else {
+                write!(f, "{}={}", k, REDACTED)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_phone() {
+        assert_eq!(phone("13812341234", true), "138****1234");
+        assert_eq!(phone("13812341234", false), "13812341234");
+        assert_eq!(phone("+8613812341234", true), "+86*******1234");
+        assert_eq!(phone("1234", true), "****");
+    }
+
+
+    #[test]
+    fn test_params() {
+        let mut params = HashMap::new();
+        params.insert("app_id", "123".to_string());
+        params.insert("app_secret", "secret".to_string());
+        params.insert("access_token", "abc".to_string());
+        params.insert("sign", "xyz".to_string());
+        params.insert("token", "sms token".to_string());
+        params.insert("randcode", "123456".to_string());
+        params.insert("phone", "13812341234".to_string());
+
+        assert_eq!(Params::new(&params, true).to_string(),
+                   "access_token=<redacted> app_id=123 app_secret=<redacted> \
+                    phone=138****1234 randcode=<redacted> sign=<redacted> token=<redacted>");
+        assert_eq!(Params::new(&params, false).to_string(),
+                   "access_token=<redacted> app_id=123 app_secret=<redacted> \
+                    phone=13812341234 randcode=<redacted> sign=<redacted> token=<redacted>");
+    }
+}
@@ -0,0 +1,352 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use hyper::client::IntoUrl;
+
+use url::Url;
+
+use super::errors::*;
+use super::phone::PhoneNumber;
+use super::redact;
+
+pub const PATH_ACCESS_TOKEN: &'static str = "emp/oauth2/v3/access_token";
+pub const PATH_AUTHORIZE: &'static str = "emp/oauth2/v3/authorize";
+pub const PATH_SMS_TOKEN: &'static str = "v2/dm/randcode/token";
+pub const PATH_SMS_SEND_WITH_CODE: &'static str = "v2/dm/randcode/sendSms";
+pub const PATH_SMS_SEND_WITH_CALLBACK: &'static str = "v2/dm/randcode/send";
+pub const PATH_SMS_SEND_TEMPLATE: &'static str = "v2/emp/templateSms/sendSms";
+
+pub const SMS_CODE_LENGTH: usize = 6;
+
+
+/// Configuration for sending of locally generated SMS verification code.
+///
+/// This struct is not meant to be used directly; see [`SmsCodeConfig::prepared`]
+/// instead.
+///
+/// [`SmsCodeConfig::prepared`]: enum.SmsCodeConfig.html#method.prepared
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PreparedSmsCode<'a> {
+    phone: Cow<'a, str>,
+    code: Cow<'a, str>,
+    expire_time: Option<usize>,
+}
+
+
+// the code is left out, lest it's logged along with the configuration
+impl<'a> fmt::Debug for PreparedSmsCode<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PreparedSmsCode")
+            .field("phone", &self.phone)
+            .field("code", &redact::REDACTED)
+            .field("expire_time", &self.expire_time)
+            .finish()
+    }
+}
+
+
+/// Configuration for sending of API-generated SMS verification code.
+///
+/// This struct is not meant to be used directly; see [`SmsCodeConfig::callback`]
+/// instead.
+///
+/// [`SmsCodeConfig::callback`]: enum.SmsCodeConfig.html#method.callback
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CallbackSmsCode<'a> {
+    phone: Cow<'a, str>,
+    url: String,
+    expire_time: Option<usize>,
+}
+
+
+/// Configuration for SMS verification code.
+///
+/// The configuration either borrows or owns its strings. Owned ones, such as
+/// those obtained with [`into_owned`], can be moved across threads freely;
+/// configurations also (de)serialize with Serde, so sends can be queued up
+/// and replayed later, possibly by another process.
+///
+/// [`into_owned`]: #method.into_owned
+///
+/// # Examples
+///
+/// ```
+/// # extern crate open189;
+/// # extern crate serde_json;
+/// use open189::SmsCodeConfig;
+///
+/// # fn main() {
+/// let code = String::from("234567");
+/// let config = SmsCodeConfig::prepared("18912345678", code.as_str(), Some(5))
+///     .unwrap()
+///     .into_owned();
+///
+/// let job = serde_json::to_string(&config).unwrap();
+/// let config: SmsCodeConfig = serde_json::from_str(&job).unwrap();
+/// assert_eq!(config.phone(), "18912345678");
+/// # }
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SmsCodeConfig<'a> {
+    /// Code is generated locally, ready to be sent.
+    Prepared(PreparedSmsCode<'a>),
+    /// Code is to be generated remotely by the API server, and sent back to the
+    /// callback URL provided.
+    Callback(CallbackSmsCode<'a>),
+}
+
+
+impl<'a> SmsCodeConfig<'a> {
+    /// Construct the parameters for sending pre-generated verification code.
+    ///
+    /// The phone number is normalized and validated as a [`PhoneNumber`], and
+    /// an `Err` is returned if it's invalid.
+    ///
+    /// The code should consist of 6 digits only. Everything else would be rejected
+    /// by the API anyway, so the validation is done locally before firing the
+    /// actual request.
+    ///
+    /// Expiry time is optional and seems purely informative, given it's just
+    /// another integer formatted into the fixed SMS template. The value is
+    /// expected to be in minutes, as suggested by the wording of the template.
+    /// Defaults to 2 minutes if not specified.
+    ///
+    /// [`PhoneNumber`]: phone/struct.PhoneNumber.html
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use open189::SmsCodeConfig;
+    ///
+    /// // use default expiry time
+    /// let config = SmsCodeConfig::prepared("18912345678", "234567", None).unwrap();
+    ///
+    /// // manually specify an expiry time of 5 min
+    /// let config = SmsCodeConfig::prepared("+86 189-1234-5678", "234567", Some(5)).unwrap();
+    /// assert_eq!(config.phone(), "18912345678");
+    /// ```
+    pub fn prepared<P, C>(phone: P,
+                          code: C,
+                          expire_time: Option<usize>)
+                          -> Result<SmsCodeConfig<'a>>
+        where P: AsRef<str>,
+              C: Into<Cow<'a, str>>
+    {
+        Ok(SmsCodeConfig::Prepared(PreparedSmsCode {
+            phone: Cow::Owned(PhoneNumber::parse(phone)?.into_string()),
+            code: code.into(),
+            expire_time: expire_time,
+        }))
+    }
+
+    /// Construct the parameters for sending remotely-generated verification code.
+    ///
+    /// Instead of providing the code yourself, the API would choose one for you,
+    /// and notify you by `POST`-ing to the callback URL you provided. The phone
+    /// number and the URL are validated on construction and may fail, in which
+    /// case an `Err` would be returned.
+    ///
+    /// Expiry time is interpreted the same way as [above].
+    ///
+    /// [above]: #method.prepared
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use open189::SmsCodeConfig;
+    ///
+    /// let url = "https://api.example.com/v1/callback/sms";
+    /// let config = SmsCodeConfig::callback("18912345678", url, None).unwrap();
+    /// ```
+    pub fn callback<P, U>(phone: P,
+                          callback_url: U,
+                          expire_time: Option<usize>)
+                          -> Result<SmsCodeConfig<'a>>
+        where P: AsRef<str>,
+              U: IntoUrl
+    {
+        Ok(SmsCodeConfig::Callback(CallbackSmsCode {
+            phone: Cow::Owned(PhoneNumber::parse(phone)?.into_string()),
+            url: callback_url.into_url()?.into_string(),
+            expire_time: expire_time,
+        }))
+    }
+
+    /// Get the phone number the code is to be sent to.
+    pub fn phone(&self) -> &str {
+        match *self {
+            SmsCodeConfig::Prepared(ref config) => &config.phone,
+            SmsCodeConfig::Callback(ref config) => &config.phone,
+        }
+    }
+
+    /// Convert into a configuration owning all of its data.
+    pub fn into_owned(self) -> SmsCodeConfig<'static> {
+        match self {
+            SmsCodeConfig::Prepared(config) => {
+                SmsCodeConfig::Prepared(PreparedSmsCode {
+                    phone: Cow::Owned(config.phone.into_owned()),
+                    code: Cow::Owned(config.code.into_owned()),
+                    expire_time: config.expire_time,
+                })
+            }
+            SmsCodeConfig::Callback(config) => {
+                SmsCodeConfig::Callback(CallbackSmsCode {
+                    phone: Cow::Owned(config.phone.into_owned()),
+                    url: config.url,
+                    expire_time: config.expire_time,
+                })
+            }
+        }
+    }
+}
+
+
+/// Parameters for requesting an access token with the Client Credentials flow.
+pub fn access_token_cc_params() -> HashMap<&'static str, String> {
+    let mut params = HashMap::new();
+    params.insert("grant_type", "client_credentials".to_string());
+    params
+}
+
+
+/// Parameters for exchanging an authorization code for a user access token.
+pub fn access_token_ac_params(code: &str, redirect_uri: &str) -> HashMap<&'static str, String> {
+    let mut params = HashMap::new();
+    params.insert("grant_type", "authorization_code".to_string());
+    params.insert("code", code.to_string());
+    params.insert("redirect_uri", redirect_uri.to_string());
+    params
+}
+
+
+/// Parameters for refreshing a user access token.
+pub fn refresh_token_params(refresh_token: &str) -> HashMap<&'static str, String> {
+    let mut params = HashMap::new();
+    params.insert("grant_type", "refresh_token".to_string());
+    params.insert("refresh_token", refresh_token.to_string());
+    params
+}
+
+
+/// Validates the SMS verification code config, returning the path of the
+/// endpoint to use and the request parameters.
+pub fn sms_code_params(sms_token: &str,
+                       config: SmsCodeConfig)
+                       -> Result<(&'static str, HashMap<&'static str, String>)> {
+    let mut params = HashMap::new();
+    params.insert("token", sms_token.to_string());
+
+    let path;
+    match config {
+        SmsCodeConfig::Prepared(config) => {
+            path = PATH_SMS_SEND_WITH_CODE;
+
+            let code = config.code;
+            if code.len() != SMS_CODE_LENGTH {
+                return Err(ErrorKind::WrongSmsCodeLength(code.len(), SMS_CODE_LENGTH).into());
+            }
+            if let Some(position) = code.chars().position(|ch| !ch.is_digit(10)) {
+                return Err(ErrorKind::NonDigitInSmsCode(position).into());
+            }
+            params.insert("randcode", code.to_string());
+
+            params.insert("phone", PhoneNumber::parse(&config.phone)?.into_string());
+            if let Some(expire_time) = config.expire_time {
+                params.insert("exp_time", format!("{}", expire_time));
+            }
+        }
+        SmsCodeConfig::Callback(config) => {
+            path = PATH_SMS_SEND_WITH_CALLBACK;
+
+            params.insert("phone", PhoneNumber::parse(&config.phone)?.into_string());
+            // the URL is validated on construction, but not on deserialization
+            params.insert("url", Url::parse(&config.url)?.into_string());
+            if let Some(expire_time) = config.expire_time {
+                params.insert("exp_time", format!("{}", expire_time));
+            }
+        }
+    }
+
+    Ok((path, params))
+}
+
+
+/// Validates the template SMS parameters, returning the request parameters.
+pub fn template_sms_params<I, K, V>(phone: &str,
+                                    template_id: &str,
+                                    template_params: I)
+                                    -> Result<HashMap<&'static str, String>>
+    where I: IntoIterator<Item = (K, V)>,
+          K: AsRef<str>,
+          V: AsRef<str>
+{
+    let mut template_param = BTreeMap::new();
+    for (k, v) in template_params {
+        let (k, v) = (k.as_ref(), v.as_ref());
+        if k.is_empty() || !k.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_') {
+            return Err(ErrorKind::InvalidTemplateParamName(k.to_string()).into());
+        }
+        if v.is_empty() {
+            return Err(ErrorKind::EmptyTemplateParamValue(k.to_string()).into());
+        }
+        template_param.insert(k.to_string(), v.to_string());
+    }
+
+    let mut params = HashMap::new();
+    params.insert("acceptor_tel", PhoneNumber::parse(phone)?.into_string());
+    params.insert("template_id", template_id.to_string());
+    params.insert("template_param", ::serde_json::to_string(&template_param)?);
+    Ok(params)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_template_sms_params() {
+        let params = template_sms_params("18912345678", "91000001", vec![("b", "2"), ("a", "1")])
+            .unwrap();
+        assert_eq!(params["acceptor_tel"], "18912345678");
+        assert_eq!(params["template_id"], "91000001");
+        assert_eq!(params["template_param"], r#"{"a":"1","b":"2"}"#);
+
+        assert!(template_sms_params("18912345678", "91000001", vec![("", "1")]).is_err());
+        assert!(template_sms_params("18912345678", "91000001", vec![("a-b", "1")]).is_err());
+        assert!(template_sms_params("18912345678", "91000001", vec![("a", "")]).is_err());
+    }
+
+
+    #[test]
+    fn test_sms_code_kept_secret() {
+        let config = SmsCodeConfig::prepared("18912345678", "234567", None).unwrap();
+        assert!(!format!("{:?}", config).contains("234567"));
+
+        let config = SmsCodeConfig::prepared("18912345678", "23456a", None).unwrap();
+        let e = sms_code_params("token", config).unwrap_err();
+        match *e.kind() {
+            ErrorKind::NonDigitInSmsCode(5) => {}
+            ref kind => panic!("unexpected error: {:?}", kind),
+        }
+        assert!(!format!("{:?}", e).contains("23456a"));
+    }
+
+
+    #[test]
+    fn test_sms_code_config_serde() {
+        let config = SmsCodeConfig::callback("18912345678", "https://example.com/cb", Some(5))
+            .unwrap()
+            .into_owned();
+        let json = ::serde_json::to_string(&config).unwrap();
+        let config: SmsCodeConfig<'static> = ::serde_json::from_str(&json).unwrap();
+        let (path, params) = sms_code_params("token", config).unwrap();
+        assert_eq!(path, PATH_SMS_SEND_WITH_CALLBACK);
+        assert_eq!(params["phone"], "18912345678");
+        assert_eq!(params["url"], "https://example.com/cb");
+        assert_eq!(params["exp_time"], "5");
+    }
+}
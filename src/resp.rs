@@ -11,6 +11,10 @@ pub trait IntoResult {
 }
 
 
+/// Response of the OAuth access token endpoint.
+///
+/// The user-specific fields are only present for tokens authorized by users,
+/// i.e. those obtained with the Authorization Code or Refresh Token grants.
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct AccessTokenResponse {
@@ -19,6 +23,20 @@ pub struct AccessTokenResponse {
     state: String,
     access_token: Option<String>,
     expires_in: Option<u64>,
+    open_id: Option<String>,
+    refresh_token: Option<String>,
+    scope: Option<String>,
+}
+
+
+impl AccessTokenResponse {
+    fn into_error(self, http_status: StatusCode) -> Error {
+        ErrorKind::ApiError(http_status,
+                            self.res_code,
+                            Some(self.state),
+                            self.res_message)
+            .into()
+    }
 }
 
 
@@ -26,18 +44,41 @@ impl IntoResult for AccessTokenResponse {
     type Item = msg::AccessToken;
 
     fn into_result(self, http_status: StatusCode) -> Result<Self::Item> {
-        if self.res_code == 0 {
+        if self.res_code == 0 && self.access_token.is_some() && self.expires_in.is_some() {
             Ok(msg::AccessToken {
                 state: self.state,
                 token: self.access_token.unwrap(),
                 expires_in: self.expires_in.unwrap(),
             })
         } else {
-            Err(ErrorKind::ApiError(http_status,
-                                    self.res_code,
-                                    Some(self.state),
-                                    self.res_message)
-                .into())
+            Err(self.into_error(http_status))
+        }
+    }
+}
+
+
+/// Response of the OAuth access token endpoint, for user-authorized tokens.
+#[derive(Deserialize)]
+pub struct UserAccessTokenResponse(AccessTokenResponse);
+
+
+impl IntoResult for UserAccessTokenResponse {
+    type Item = msg::UserAccessToken;
+
+    fn into_result(self, http_status: StatusCode) -> Result<Self::Item> {
+        let resp = self.0;
+        if resp.res_code == 0 && resp.access_token.is_some() && resp.expires_in.is_some() &&
+           resp.open_id.is_some() && resp.refresh_token.is_some() {
+            Ok(msg::UserAccessToken {
+                state: resp.state,
+                token: resp.access_token.unwrap(),
+                expires_in: resp.expires_in.unwrap(),
+                open_id: resp.open_id.unwrap(),
+                refresh_token: resp.refresh_token.unwrap(),
+                scope: resp.scope,
+            })
+        } else {
+            Err(resp.into_error(http_status))
         }
     }
 }
@@ -89,3 +130,28 @@ impl IntoResult for SmsCodeResponse {
         }
     }
 }
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateSmsResponse {
+    res_code: u64,
+    res_message: Option<String>,
+    // the API misspells this as "idertifier"; accept both in case it's fixed
+    identifier: Option<String>,
+    idertifier: Option<String>,
+}
+
+
+impl IntoResult for TemplateSmsResponse {
+    type Item = msg::SentTemplateSms;
+
+    fn into_result(self, http_status: StatusCode) -> Result<Self::Item> {
+        match (self.res_code, self.identifier.or(self.idertifier)) {
+            (0, Some(sms_id)) => Ok(msg::SentTemplateSms { sms_id: sms_id }),
+            _ => {
+                Err(ErrorKind::ApiError(http_status, self.res_code, None, self.res_message).into())
+            }
+        }
+    }
+}
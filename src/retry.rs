@@ -0,0 +1,232 @@
+//! Automatic retrying of failed requests.
+//!
+//! By default every request is attempted exactly once. Install a
+//! [`RetryPolicy`] with [`Open189App::set_retry_policy`] to have transient
+//! failures retried with exponential backoff.
+//!
+//! Every attempt is freshly timestamped and signed. Requests sending SMS are
+//! not idempotent though: if the request may have reached the API, retrying
+//! could deliver the message twice. These requests are thus only retried on
+//! failures that provably happened before the request was sent, regardless
+//! of the policy.
+//!
+//! [`RetryPolicy`]: struct.RetryPolicy.html
+//! [`Open189App::set_retry_policy`]: ../struct.Open189App.html#method.set_retry_policy
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper;
+
+use rand;
+use rand::Rng;
+
+use super::errors::*;
+
+/// Default delay before the first retry, in milliseconds.
+const DEFAULT_INITIAL_BACKOFF_MS: u64 = 100;
+/// Default upper bound of the delay between retries, in milliseconds.
+const DEFAULT_MAX_BACKOFF_MS: u64 = 5000;
+
+
+/// Policy for retrying failed requests.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use open189::Open189App;
+/// use open189::retry::RetryPolicy;
+///
+/// let policy = RetryPolicy::new(3)
+///     .backoff(Duration::from_millis(200), Duration::from_secs(2))
+///     .retry_if(|e| e.is_retryable());
+///
+/// let mut client = Open189App::new("your app id here", "your app secret here");
+/// client.set_retry_policy(policy);
+/// ```
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    retryable: Arc<Fn(&Error) -> bool + Send + Sync>,
+}
+
+
+impl RetryPolicy {
+    /// Construct a policy making at most `max_attempts` attempts in total.
+    ///
+    /// Errors are considered retryable according to [`Error::is_retryable`].
+    /// The delay between attempts starts at 100 ms, doubling every time up to
+    /// 5 s, with random jitter applied.
+    ///
+    /// [`Error::is_retryable`]: ../errors/struct.Error.html#method.is_retryable
+    pub fn new(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: max_attempts,
+            initial_backoff: Duration::from_millis(DEFAULT_INITIAL_BACKOFF_MS),
+            max_backoff: Duration::from_millis(DEFAULT_MAX_BACKOFF_MS),
+            jitter: true,
+            retryable: Arc::new(Error::is_retryable),
+        }
+    }
+
+    /// Construct a policy that never retries.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::new(1)
+    }
+
+    /// Set the delay before the first retry, and the upper bound of the delay.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Enable or disable random jitter of the delay.
+    pub fn jitter(mut self, jitter: bool) -> RetryPolicy {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Set the predicate deciding which errors are retryable.
+    pub fn retry_if<F>(mut self, f: F) -> RetryPolicy
+        where F: Fn(&Error) -> bool + Send + Sync + 'static
+    {
+        self.retryable = Arc::new(f);
+        self
+    }
+
+    /// Get the maximum number of attempts.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Whether to make another attempt after attempt number `attempt`
+    /// (counting from 1) failed with `error`.
+    ///
+    /// Non-idempotent requests are only retried if the error provably
+    /// happened before the request was sent.
+    pub fn should_retry(&self, error: &Error, attempt: u32, idempotent: bool) -> bool {
+        if attempt >= self.max_attempts {
+            return false;
+        }
+        if !idempotent && !is_pre_send_failure(error) {
+            return false;
+        }
+        (self.retryable)(error)
+    }
+
+    /// Get the delay before the attempt following attempt number `attempt`
+    /// (counting from 1).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let initial = duration_to_ms(self.initial_backoff);
+        let max = duration_to_ms(self.max_backoff);
+
+        let shift = ::std::cmp::min(attempt.saturating_sub(1), 32);
+        let delay = ::std::cmp::min(initial.saturating_mul(1 << shift), max);
+
+        let delay = if self.jitter && delay > 0 {
+            // "equal jitter": keep half of the delay, randomize the rest
+            delay / 2 + rand::thread_rng().gen_range(0, delay / 2 + 1)
+        } else {
+            delay
+        };
+        Duration::from_millis(delay)
+    }
+}
+
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::none()
+    }
+}
+
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+
+fn duration_to_ms(d: Duration) -> u64 {
+    d.as_secs().saturating_mul(1000).saturating_add(d.subsec_nanos() as u64 / 1_000_000)
+}
+
+
+/// Whether the error provably happened before the request reached the
+/// network, so it's safe to retry even non-idempotent requests.
+///
+/// This is deliberately conservative; only failures to establish the
+/// connection in the first place qualify.
+pub fn is_pre_send_failure(error: &Error) -> bool {
+    match *error.kind() {
+        ErrorKind::Timeout(TimeoutKind::Connect) => true,
+        ErrorKind::HyperError(hyper::Error::Io(ref e)) => {
+            match e.kind() {
+                io::ErrorKind::ConnectionRefused |
+                io::ErrorKind::AddrNotAvailable => true,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::new(10)
+            .backoff(Duration::from_millis(100), Duration::from_millis(1000))
+            .jitter(false);
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(4), Duration::from_millis(800));
+        assert_eq!(policy.delay(5), Duration::from_millis(1000));
+        assert_eq!(policy.delay(100), Duration::from_millis(1000));
+
+        let policy = policy.jitter(true);
+        for attempt in 1..10 {
+            let delay = policy.delay(attempt);
+            assert!(delay <= Duration::from_millis(1000));
+        }
+    }
+
+
+    #[test]
+    fn test_should_retry() {
+        let refused: Error =
+            hyper::Error::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")).into();
+        let reset: Error =
+            hyper::Error::Io(io::Error::new(io::ErrorKind::ConnectionReset, "reset")).into();
+
+        let policy = RetryPolicy::new(3);
+        assert!(policy.should_retry(&refused, 1, false));
+        assert!(policy.should_retry(&reset, 2, true));
+        assert!(!policy.should_retry(&reset, 1, false));
+        assert!(!policy.should_retry(&refused, 3, true));
+        assert!(!RetryPolicy::none().should_retry(&refused, 1, true));
+
+        let connect_timeout: Error = ErrorKind::Timeout(TimeoutKind::Connect).into();
+        let response_timeout: Error = ErrorKind::Timeout(TimeoutKind::Response).into();
+        assert!(policy.should_retry(&connect_timeout, 1, false));
+        assert!(!policy.should_retry(&response_timeout, 1, false));
+    }
+}
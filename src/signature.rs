@@ -0,0 +1,170 @@
+//! Request signatures.
+//!
+//! The API authenticates requests, and its own callbacks, with an HMAC-SHA1
+//! signature of the request parameters keyed with the app secret, passed as
+//! the `sign` parameter. The parameters are first put into a canonical form:
+//! sorted by name and joined as `name=value` pairs with `&`, without any URL
+//! encoding, and leaving out `sign` itself. The signature is the Base64 of
+//! the HMAC of that string.
+//!
+//! Requests made by this crate are signed automatically; this module is
+//! useful for verifying inbound requests in other contexts, and for
+//! debugging requests the API rejects for their signature, by comparing
+//! [`canonical_string`] against what the API expects.
+//!
+//! # Test vectors
+//!
+//! | Parameters | Secret | Signature |
+//! |------------|--------|-----------|
+//! | `k1=v1&k2=v2&k3=v3` | `012345` | `iAKpGb9i8EKY8q4HPfiMdfb27OM=` |
+//! | (none) | `012345` | `QbuLQ1gV765MrkhOidq7Yf/r5+s=` |
+//! | `phone=18912345678&rand_code=123456` | `012345` | `gWYzKF0JlL109xZA2hEA0PSpzaY=` |
+//! | `access_token=abc&app_id=123&timestamp=2016-10-18 12:00:00` | `secret` | `QDUtmoswVb2Pqr+JbKuAvIt0KRI=` |
+//!
+//! # Examples
+//!
+//! ```
+//! use std::collections::HashMap;
+//!
+//! use open189::signature;
+//!
+//! let mut params = HashMap::new();
+//! params.insert("k2", "v2");
+//! params.insert("k1", "v1");
+//! params.insert("k3", "v3");
+//!
+//! assert_eq!(signature::canonical_string(&params), "k1=v1&k2=v2&k3=v3");
+//! assert_eq!(signature::sign(&params, "012345"), "iAKpGb9i8EKY8q4HPfiMdfb27OM=");
+//! assert!(signature::verify(&params, "012345", "iAKpGb9i8EKY8q4HPfiMdfb27OM="));
+//! ```
+//!
+//! [`canonical_string`]: fn.canonical_string.html
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use crypto::util::fixed_time_eq;
+
+use super::util;
+
+/// Name of the parameter carrying the signature.
+const SIGN_PARAM: &'static str = "sign";
+
+
+/// Get the canonical string of the parameters, i.e. the exact string that is
+/// signed.
+///
+/// A `sign` parameter, if present, is left out.
+pub fn canonical_string<K, V>(params: &HashMap<K, V>) -> String
+    where K: AsRef<str> + Eq + Hash,
+          V: AsRef<str>
+{
+    let mut kv_list: Vec<_> = params.iter()
+        .map(|(k, v)| (k.as_ref(), v.as_ref()))
+        .filter(|&(k, _)| k != SIGN_PARAM)
+        .collect();
+    kv_list.sort_by(|a, b| a.0.cmp(b.0));
+    let mut result = String::new();
+    for (i, (k, v)) in kv_list.into_iter().enumerate() {
+        if i > 0 {
+            result.push('&');
+        }
+        result.push_str(k);
+        result.push('=');
+        result.push_str(v);
+    }
+    result
+}
+
+
+/// Compute the signature of the parameters with the app secret.
+pub fn sign<K, V, S>(params: &HashMap<K, V>, secret: S) -> String
+    where K: AsRef<str> + Eq + Hash,
+          V: AsRef<str>,
+          S: AsRef<str>
+{
+    let payload = canonical_string(params);
+
+    let mut hmac = Hmac::new(Sha1::new(), secret.as_ref().as_bytes());
+    hmac.input(payload.as_bytes());
+
+    util::b64encode(hmac.result().code())
+}
+
+
+/// Check the signature of the parameters against the app secret.
+///
+/// The comparison takes constant time, so as not to leak the expected
+/// signature through timing.
+pub fn verify<K, V, S, T>(params: &HashMap<K, V>, secret: S, sign: T) -> bool
+    where K: AsRef<str> + Eq + Hash,
+          V: AsRef<str>,
+          S: AsRef<str>,
+          T: AsRef<str>
+{
+    let expected = self::sign(params, secret);
+    fixed_time_eq(expected.as_bytes(), sign.as_ref().as_bytes())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn params(pairs: &[(&'static str, &'static str)]) -> HashMap<&'static str, &'static str> {
+        pairs.iter().cloned().collect()
+    }
+
+
+    #[test]
+    fn test_canonical_string() {
+        let x = params(&[("k2", "v2"), ("k3", "v3"), ("k1", "v1")]);
+        assert_eq!(canonical_string(&x), "k1=v1&k2=v2&k3=v3");
+
+        let x = params(&[("k1", "v1"), ("sign", "abc")]);
+        assert_eq!(canonical_string(&x), "k1=v1");
+
+        let x = params(&[("timestamp", "2016-10-18 12:00:00"), ("url", "http://a/?b=c&d")]);
+        assert_eq!(canonical_string(&x),
+                   "timestamp=2016-10-18 12:00:00&url=http://a/?b=c&d");
+    }
+
+
+    #[test]
+    fn test_sign() {
+        let vectors = [(params(&[("k2", "v2"), ("k3", "v3"), ("k1", "v1")]),
+                        "012345",
+                        "iAKpGb9i8EKY8q4HPfiMdfb27OM="),
+                       (params(&[]), "012345", "QbuLQ1gV765MrkhOidq7Yf/r5+s="),
+                       (params(&[("rand_code", "123456"), ("phone", "18912345678")]),
+                        "012345",
+                        "gWYzKF0JlL109xZA2hEA0PSpzaY="),
+                       (params(&[("app_id", "123"),
+                                 ("timestamp", "2016-10-18 12:00:00"),
+                                 ("access_token", "abc")]),
+                        "secret",
+                        "QDUtmoswVb2Pqr+JbKuAvIt0KRI=")];
+        for &(ref x, secret, expected) in &vectors {
+            assert_eq!(sign(x, secret), expected);
+        }
+    }
+
+
+    #[test]
+    fn test_verify() {
+        let x = params(&[("k2", "v2"), ("k3", "v3"), ("k1", "v1")]);
+        assert!(verify(&x, "012345", "iAKpGb9i8EKY8q4HPfiMdfb27OM="));
+        assert!(!verify(&x, "012345", "iAKpGb9i8EKY8q4HPfiMdfb27OA="));
+        assert!(!verify(&x, "012345", ""));
+        assert!(!verify(&x, "543210", "iAKpGb9i8EKY8q4HPfiMdfb27OM="));
+
+        // the sign parameter itself isn't signed
+        let mut x = x;
+        x.insert("sign", "iAKpGb9i8EKY8q4HPfiMdfb27OM=");
+        assert!(verify(&x, "012345", "iAKpGb9i8EKY8q4HPfiMdfb27OM="));
+    }
+}
@@ -0,0 +1,754 @@
+//! Access token management.
+//!
+//! Access tokens obtained with the Client Credentials flow are reusable until
+//! they expire, and the API frowns upon clients requesting new ones all the
+//! time. [`TokenManager`] takes care of caching and refreshing the token for
+//! you.
+//!
+//! Where the token is kept is up to the [`TokenStore`] in use. By default it
+//! lives in memory, but a [`FileTokenStore`] lets every process on a host
+//! share a single token.
+//!
+//! [`TokenManager`]: struct.TokenManager.html
+//! [`TokenStore`]: trait.TokenStore.html
+//! [`FileTokenStore`]: struct.FileTokenStore.html
+
+use std::ffi::OsString;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fs2::FileExt;
+
+use hyper::client::Client;
+
+use rand;
+
+use super::app::Open189App;
+use super::clock::Clock;
+use super::errors::*;
+use super::msg;
+use super::token_cache::{DEFAULT_REFRESH_MARGIN_SECS, TokenCache};
+use super::transport::Transport;
+
+
+
+/// An access token along with the time it was issued.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct StoredToken {
+    /// The access token.
+    pub token: msg::AccessToken,
+    /// The time the access token was issued.
+    pub issued_at: SystemTime,
+}
+
+
+impl StoredToken {
+    /// Wrap an access token that's just been issued, according to the clock.
+    pub fn new(token: msg::AccessToken, clock: &Clock) -> StoredToken {
+        StoredToken {
+            token: token,
+            issued_at: clock.now(),
+        }
+    }
+
+    /// Whether the token is going to stay valid for at least `margin`,
+    /// according to the clock.
+    pub fn is_fresh(&self, margin: Duration, clock: &Clock) -> bool {
+        let ttl = Duration::from_secs(self.token.expires_in);
+        match clock.now().duration_since(self.issued_at) {
+            Ok(age) => age + margin < ttl,
+            // the clock went backwards; better be safe than sorry
+            Err(_) => false,
+        }
+    }
+}
+
+
+/// Storage backend for cached access tokens.
+///
+/// Stores hold at most one token at a time. Implementations must be safe to
+/// share between threads; whether they're also shared between processes is
+/// up to the implementation.
+pub trait TokenStore: Send + Sync {
+    /// Load the stored token, if any.
+    fn load(&self) -> Result<Option<StoredToken>>;
+
+    /// Unconditionally store the token, replacing any existing one.
+    fn save(&self, token: &StoredToken) -> Result<()>;
+
+    /// Store `new` only if the currently stored token is `current`, returning
+    /// whether the token is replaced.
+    ///
+    /// Tokens are compared by their access token string; `None` matches an
+    /// empty store.
+    fn compare_and_swap(&self,
+                        current: Option<&StoredToken>,
+                        new: &StoredToken)
+                        -> Result<bool>;
+
+    /// Remove the stored token, if any.
+    fn clear(&self) -> Result<()>;
+
+    /// Atomically update the stored token.
+    ///
+    /// `f` is called with the current token and returns the token to keep,
+    /// which is stored unless it's the current one. Stores able to lock out
+    /// other threads and processes for the duration should do so, so that
+    /// only one of them requests a new token at a time. The default
+    /// implementation relies on `compare_and_swap` instead: if another
+    /// update gets in between, `f` is called again with the newly stored
+    /// token.
+    fn update(&self,
+              f: &mut FnMut(Option<&StoredToken>) -> Result<StoredToken>)
+              -> Result<StoredToken> {
+        loop {
+            let current = self.load()?;
+            let new = f(current.as_ref())?;
+            if same_token(current.as_ref(), Some(&new)) ||
+               self.compare_and_swap(current.as_ref(), &new)? {
+                return Ok(new);
+            }
+        }
+    }
+}
+
+
+impl<S: TokenStore + ?Sized> TokenStore for Arc<S> {
+    fn load(&self) -> Result<Option<StoredToken>> {
+        (**self).load()
+    }
+
+    fn save(&self, token: &StoredToken) -> Result<()> {
+        (**self).save(token)
+    }
+
+    fn compare_and_swap(&self,
+                        current: Option<&StoredToken>,
+                        new: &StoredToken)
+                        -> Result<bool> {
+        (**self).compare_and_swap(current, new)
+    }
+
+    fn clear(&self) -> Result<()> {
+        (**self).clear()
+    }
+
+    fn update(&self,
+              f: &mut FnMut(Option<&StoredToken>) -> Result<StoredToken>)
+              -> Result<StoredToken> {
+        (**self).update(f)
+    }
+}
+
+
+fn same_token(a: Option<&StoredToken>, b: Option<&StoredToken>) -> bool {
+    a.map(|t| &t.token.token) == b.map(|t| &t.token.token)
+}
+
+
+/// In-memory token store, private to the current process.
+#[derive(Debug, Default)]
+pub struct MemoryTokenStore {
+    token: Mutex<Option<StoredToken>>,
+}
+
+
+impl MemoryTokenStore {
+    /// Construct an empty store.
+    pub fn new() -> MemoryTokenStore {
+        Default::default()
+    }
+}
+
+
+impl TokenStore for MemoryTokenStore {
+    fn load(&self) -> Result<Option<StoredToken>> {
+        Ok(self.token.lock().unwrap().clone())
+    }
+
+    fn save(&self, token: &StoredToken) -> Result<()> {
+        *self.token.lock().unwrap() = Some(token.clone());
+        Ok(())
+    }
+
+    fn compare_and_swap(&self,
+                        current: Option<&StoredToken>,
+                        new: &StoredToken)
+                        -> Result<bool> {
+        let mut token = self.token.lock().unwrap();
+        if !same_token(token.as_ref(), current) {
+            return Ok(false);
+        }
+
+        *token = Some(new.clone());
+        Ok(true)
+    }
+
+    fn clear(&self) -> Result<()> {
+        *self.token.lock().unwrap() = None;
+        Ok(())
+    }
+
+    fn update(&self,
+              f: &mut FnMut(Option<&StoredToken>) -> Result<StoredToken>)
+              -> Result<StoredToken> {
+        let mut token = self.token.lock().unwrap();
+        let new = f(token.as_ref())?;
+        *token = Some(new.clone());
+        Ok(new)
+    }
+}
+
+
+/// On-disk representation of a `StoredToken`.
+#[derive(Serialize, Deserialize)]
+struct TokenFile {
+    state: String,
+    access_token: String,
+    expires_in: u64,
+    issued_at: u64,
+}
+
+
+impl TokenFile {
+    fn from_stored(token: &StoredToken) -> TokenFile {
+        let issued_at = token.issued_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        TokenFile {
+            state: token.token.state.clone(),
+            access_token: token.token.token.clone(),
+            expires_in: token.token.expires_in,
+            issued_at: issued_at,
+        }
+    }
+
+    fn into_stored(self) -> StoredToken {
+        StoredToken {
+            token: msg::AccessToken {
+                state: self.state,
+                token: self.access_token,
+                expires_in: self.expires_in,
+            },
+            issued_at: UNIX_EPOCH + Duration::from_secs(self.issued_at),
+        }
+    }
+}
+
+
+/// File-backed token store, shared between processes on the same host.
+///
+/// The token is kept as JSON in the given file, which is only readable and
+/// writable by its owner on Unix. Updates are written to a temporary file
+/// first and atomically renamed into place, and all accesses are serialized
+/// with an advisory lock on a sibling `.lock` file. Updates hold the lock
+/// exclusively while a new token is requested, so processes sharing the file
+/// request one token between them.
+///
+/// # Examples
+///
+/// ```no_run
+/// use open189::Open189App;
+/// use open189::token::{FileTokenStore, TokenManager};
+///
+/// let app = Open189App::new("your app id here", "your app secret here");
+/// let store = FileTokenStore::new("/var/cache/myapp/open189-token.json");
+/// let manager = TokenManager::with_store(app, store);
+/// ```
+#[derive(Debug)]
+pub struct FileTokenStore {
+    path: PathBuf,
+    lock_path: PathBuf,
+}
+
+
+impl FileTokenStore {
+    /// Construct a store keeping the token in the given file.
+    pub fn new<P: AsRef<Path>>(path: P) -> FileTokenStore {
+        let path = path.as_ref().to_path_buf();
+        let lock_path = sibling_path(&path, ".lock");
+        FileTokenStore {
+            path: path,
+            lock_path: lock_path,
+        }
+    }
+
+    /// Get the path of the token file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn lock(&self, exclusive: bool) -> Result<File> {
+        let file = OpenOptions::new().write(true).create(true).open(&self.lock_path)?;
+        if exclusive {
+            file.lock_exclusive()?;
+        } else {
+            file.lock_shared()?;
+        }
+        Ok(file)
+    }
+
+    fn read(&self) -> Result<Option<StoredToken>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let token: TokenFile = ::serde_json::from_str(&content)?;
+        Ok(Some(token.into_stored()))
+    }
+
+    fn write(&self, token: &StoredToken) -> Result<()> {
+        let content = ::serde_json::to_string(&TokenFile::from_stored(token))?;
+
+        let tmp_path = sibling_path(&self.path, &format!(".{:x}.tmp", rand::random::<u64>()));
+        {
+            let mut file = create_private(&tmp_path)?;
+            file.write_all(content.as_bytes())?;
+            file.sync_all()?;
+        }
+
+        match fs::rename(&tmp_path, &self.path) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                Err(e.into())
+            }
+        }
+    }
+}
+
+
+impl TokenStore for FileTokenStore {
+    fn load(&self) -> Result<Option<StoredToken>> {
+        let _lock = self.lock(false)?;
+        self.read()
+    }
+
+    fn save(&self, token: &StoredToken) -> Result<()> {
+        let _lock = self.lock(true)?;
+        self.write(token)
+    }
+
+    fn compare_and_swap(&self,
+                        current: Option<&StoredToken>,
+                        new: &StoredToken)
+                        -> Result<bool> {
+        let _lock = self.lock(true)?;
+        if !same_token(self.read()?.as_ref(), current) {
+            return Ok(false);
+        }
+
+        self.write(new)?;
+        Ok(true)
+    }
+
+    fn clear(&self) -> Result<()> {
+        let _lock = self.lock(true)?;
+        match fs::remove_file(&self.path) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn update(&self,
+              f: &mut FnMut(Option<&StoredToken>) -> Result<StoredToken>)
+              -> Result<StoredToken> {
+        let _lock = self.lock(true)?;
+        let current = self.read()?;
+        let new = f(current.as_ref())?;
+        if !same_token(current.as_ref(), Some(&new)) {
+            self.write(&new)?;
+        }
+        Ok(new)
+    }
+}
+
+
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut result: OsString = path.as_os_str().to_owned();
+    result.push(suffix);
+    result.into()
+}
+
+
+/// Creates a new file readable and writable by its owner only, as it's going
+/// to hold a token.
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+
+/// Caching, self-refreshing access token manager.
+///
+/// The manager wraps an [`Open189App`], requesting an access token with the
+/// Client Credentials flow on first use and caching it in a [`TokenStore`]
+/// afterwards. The token is proactively refreshed when it's about to expire
+/// according to the app's [`clock`], and concurrent refreshes from multiple
+/// threads are coalesced into one request. If the store is shared between processes, so is the token, and
+/// with a store locking out other processes while updating, such as
+/// [`FileTokenStore`], so are the refreshes.
+///
+/// [`Open189App`]: ../struct.Open189App.html
+/// [`clock`]: ../struct.Open189App.html#method.clock
+/// [`TokenStore`]: trait.TokenStore.html
+/// [`FileTokenStore`]: struct.FileTokenStore.html
+///
+/// # Examples
+///
+/// ```no_run
+/// use open189::Open189App;
+/// use open189::token::TokenManager;
+///
+/// # fn foo() -> open189::errors::Result<()> {
+/// let app = Open189App::new("your app id here", "your app secret here");
+/// let manager = TokenManager::new(app);
+///
+/// let sms_token = manager.with_access_token(|access_token| {
+///     manager.app().sms_get_token(access_token)
+/// })?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TokenManager<T: Transport = Client, S: TokenStore = MemoryTokenStore> {
+    app: Open189App<T>,
+    cache: TokenCache<S>,
+}
+
+
+impl<T: Transport> TokenManager<T, MemoryTokenStore> {
+    /// Construct a token manager caching the token in memory, with the
+    /// default refresh margin of 5 minutes.
+    pub fn new(app: Open189App<T>) -> TokenManager<T, MemoryTokenStore> {
+        TokenManager::with_store(app, MemoryTokenStore::new())
+    }
+
+    /// Construct a token manager caching the token in memory, refreshing it
+    /// the specified amount of time before it expires.
+    pub fn with_refresh_margin(app: Open189App<T>,
+                               refresh_margin: Duration)
+                               -> TokenManager<T, MemoryTokenStore> {
+        TokenManager::with_store_and_refresh_margin(app, MemoryTokenStore::new(), refresh_margin)
+    }
+}
+
+
+impl<T: Transport, S: TokenStore> TokenManager<T, S> {
+    /// Construct a token manager caching the token in the given store, with
+    /// the default refresh margin of 5 minutes.
+    pub fn with_store(app: Open189App<T>, store: S) -> TokenManager<T, S> {
+        TokenManager::with_store_and_refresh_margin(app,
+                                                    store,
+                                                    Duration::from_secs(DEFAULT_REFRESH_MARGIN_SECS))
+    }
+
+    /// Construct a token manager caching the token in the given store,
+    /// refreshing it the specified amount of time before it expires.
+    pub fn with_store_and_refresh_margin(app: Open189App<T>,
+                                         store: S,
+                                         refresh_margin: Duration)
+                                         -> TokenManager<T, S> {
+        TokenManager {
+            app: app,
+            cache: TokenCache::new(store, refresh_margin),
+        }
+    }
+
+    /// Get the wrapped client.
+    pub fn app(&self) -> &Open189App<T> {
+        &self.app
+    }
+
+    /// Get the token store in use.
+    pub fn store(&self) -> &S {
+        self.cache.store()
+    }
+
+    /// Get a valid access token, requesting a new one if necessary.
+    pub fn access_token(&self) -> Result<String> {
+        self.cache.access_token(&self.app)
+    }
+
+    /// Drop the cached access token, so the next use triggers a refresh.
+    pub fn invalidate(&self) -> Result<()> {
+        self.cache.store().clear()
+    }
+
+    /// Run the provided closure with a valid access token.
+    ///
+    /// If the API rejects the token as invalid or expired, a new one is
+    /// requested and the closure is run once more.
+    pub fn with_access_token<F, R>(&self, f: F) -> Result<R>
+        where F: Fn(&str) -> Result<R>
+    {
+        let token = self.access_token()?;
+        match f(&token) {
+            Err(ref e) if e.api_error_code().map_or(false, |c| c.is_token_error()) => {}
+            result => return result,
+        }
+
+        let token = self.cache.refresh(&self.app, Some(&token))?;
+        f(&token)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+    use super::super::clock::FixedClock;
+
+
+    /// A fresh directory under the system temporary directory, removed when
+    /// dropped.
+    struct TempDir(PathBuf);
+
+
+    impl TempDir {
+        fn new() -> TempDir {
+            let path = env::temp_dir().join(format!("open189-test-{:x}", rand::random::<u64>()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn entries(&self) -> Vec<String> {
+            let mut entries: Vec<_> = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            entries.sort();
+            entries
+        }
+    }
+
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+
+    /// The time the tests take place at.
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1500000000)
+    }
+
+
+    fn stored_token(token: &str, expires_in: u64, age: u64) -> StoredToken {
+        StoredToken {
+            token: msg::AccessToken {
+                state: "state".to_string(),
+                token: token.to_string(),
+                expires_in: expires_in,
+            },
+            issued_at: now() - Duration::from_secs(age),
+        }
+    }
+
+
+    #[test]
+    fn test_stored_token_freshness() {
+        let clock = FixedClock::new(now());
+        let margin = Duration::from_secs(60);
+        assert!(stored_token("t", 3600, 0).is_fresh(margin, &clock));
+        assert!(stored_token("t", 3600, 3000).is_fresh(margin, &clock));
+        assert!(!stored_token("t", 3600, 3550).is_fresh(margin, &clock));
+        assert!(!stored_token("t", 3600, 7200).is_fresh(margin, &clock));
+
+        // issued in the future, so the clock must have gone backwards
+        clock.set(now() - Duration::from_secs(10));
+        assert!(!stored_token("t", 3600, 0).is_fresh(margin, &clock));
+
+        let issued = StoredToken::new(stored_token("t", 3600, 0).token, &clock);
+        assert_eq!(issued.issued_at, clock.now());
+    }
+
+
+    #[test]
+    fn test_memory_store_compare_and_swap() {
+        let store = MemoryTokenStore::new();
+        let a = stored_token("a", 3600, 0);
+        let b = stored_token("b", 3600, 0);
+
+        assert!(!store.compare_and_swap(Some(&a), &b).unwrap());
+        assert!(store.compare_and_swap(None, &a).unwrap());
+        assert!(!store.compare_and_swap(None, &b).unwrap());
+        assert!(store.compare_and_swap(Some(&a), &b).unwrap());
+        assert_eq!(store.load().unwrap(), Some(b));
+    }
+
+
+    #[test]
+    fn test_file_store() {
+        let dir = TempDir::new();
+        let store = FileTokenStore::new(dir.0.join("token.json"));
+        let a = stored_token("a", 3600, 0);
+        let b = stored_token("b", 3600, 0);
+
+        assert_eq!(store.load().unwrap(), None);
+        store.save(&a).unwrap();
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.token, a.token);
+        assert_eq!(loaded.issued_at.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                   a.issued_at.duration_since(UNIX_EPOCH).unwrap().as_secs());
+
+        assert!(!store.compare_and_swap(None, &b).unwrap());
+        assert!(store.compare_and_swap(Some(&a), &b).unwrap());
+        assert_eq!(store.load().unwrap().unwrap().token, b.token);
+
+        // writes go through a renamed temporary file, leaving nothing behind
+        assert_eq!(dir.entries(), vec!["token.json", "token.json.lock"]);
+
+        store.clear().unwrap();
+        assert_eq!(store.load().unwrap(), None);
+        store.clear().unwrap();
+    }
+
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_store_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let store = FileTokenStore::new(dir.0.join("token.json"));
+        store.save(&stored_token("a", 3600, 0)).unwrap();
+
+        let mode = fs::metadata(store.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+
+    #[test]
+    fn test_file_store_locking() {
+        let dir = TempDir::new();
+        let path = dir.0.join("token.json");
+        let store = FileTokenStore::new(&path);
+        store.save(&stored_token("a", 3600, 0)).unwrap();
+
+        let lock = store.lock(true).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let other = FileTokenStore::new(&path);
+        thread::spawn(move || {
+            let _ = tx.send(other.load().map(|token| token.unwrap().token.token));
+        });
+
+        // readers wait for the exclusive lock to be released
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(lock);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap(), "a");
+    }
+
+
+    #[test]
+    fn test_file_store_update_coalesces() {
+        let dir = TempDir::new();
+        let path = dir.0.join("token.json");
+        let fetches = Arc::new(AtomicUsize::new(0));
+
+        // separate store instances, as if in separate processes
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let store = FileTokenStore::new(&path);
+                let fetches = fetches.clone();
+                thread::spawn(move || {
+                    store.update(&mut |current| {
+                            if let Some(current) = current {
+                                return Ok(current.clone());
+                            }
+                            thread::sleep(Duration::from_millis(50));
+                            fetches.fetch_add(1, Ordering::SeqCst);
+                            Ok(stored_token(&i.to_string(), 3600, 0))
+                        })
+                        .unwrap()
+                        .token
+                        .token
+                })
+            })
+            .collect();
+        let tokens: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert!(tokens.iter().all(|token| *token == tokens[0]));
+    }
+
+
+    #[test]
+    fn test_default_update_retries_on_race() {
+        /// A store whose first compare-and-swap loses to another writer.
+        struct RacyStore {
+            inner: MemoryTokenStore,
+            raced: Mutex<bool>,
+        }
+
+        impl TokenStore for RacyStore {
+            fn load(&self) -> Result<Option<StoredToken>> {
+                self.inner.load()
+            }
+
+            fn save(&self, token: &StoredToken) -> Result<()> {
+                self.inner.save(token)
+            }
+
+            fn compare_and_swap(&self,
+                                current: Option<&StoredToken>,
+                                new: &StoredToken)
+                                -> Result<bool> {
+                let mut raced = self.raced.lock().unwrap();
+                if !*raced {
+                    *raced = true;
+                    self.inner.save(&stored_token("theirs", 3600, 0))?;
+                }
+                self.inner.compare_and_swap(current, new)
+            }
+
+            fn clear(&self) -> Result<()> {
+                self.inner.clear()
+            }
+        }
+
+        let store = RacyStore {
+            inner: MemoryTokenStore::new(),
+            raced: Mutex::new(false),
+        };
+        let mut calls = 0;
+        let token = store.update(&mut |current| {
+                calls += 1;
+                match current {
+                    Some(current) => Ok(current.clone()),
+                    None => Ok(stored_token("ours", 3600, 0)),
+                }
+            })
+            .unwrap();
+
+        // the winner's token is kept rather than overwritten
+        assert_eq!(calls, 2);
+        assert_eq!(token.token.token, "theirs");
+        assert_eq!(store.load().unwrap().unwrap().token.token, "theirs");
+    }
+}
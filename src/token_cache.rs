@@ -0,0 +1,91 @@
+//! Caching and refreshing of access tokens, shared by `Open189App` and
+//! `TokenManager`.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::app::Open189App;
+use super::clock::Clock;
+use super::errors::*;
+use super::token::{StoredToken, TokenStore};
+use super::transport::Transport;
+
+/// Default amount of time before expiry at which tokens are refreshed.
+pub const DEFAULT_REFRESH_MARGIN_SECS: u64 = 300;
+
+
+/// A Client Credentials access token kept in a store, requested on first use
+/// and refreshed when about to expire or rejected.
+pub struct TokenCache<S> {
+    store: S,
+    refresh_margin: Duration,
+    refresh_lock: Mutex<()>,
+}
+
+
+impl<S: TokenStore> TokenCache<S> {
+    pub fn new(store: S, refresh_margin: Duration) -> TokenCache<S> {
+        TokenCache {
+            store: store,
+            refresh_margin: refresh_margin,
+            refresh_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Gets the stored token, unless it's missing or about to expire
+    /// according to the clock.
+    pub fn cached_token(&self, clock: &Clock) -> Result<Option<String>> {
+        Ok(self.store
+            .load()?
+            .and_then(|stored| if stored.is_fresh(self.refresh_margin, clock) {
+                Some(stored.token.token)
+            } else {
+                None
+            }))
+    }
+
+    /// Gets a valid token, requesting a new one with the client if necessary.
+    /// Tokens are aged by the client's clock.
+    pub fn access_token<T: Transport>(&self, app: &Open189App<T>) -> Result<String> {
+        match self.cached_token(app.clock())? {
+            Some(token) => Ok(token),
+            None => self.refresh(app, None),
+        }
+    }
+
+    /// Requests a new token, unless another thread or process has already
+    /// done so while we were waiting. `stale` is the token known to be
+    /// rejected, if any.
+    pub fn refresh<T: Transport>(&self,
+                                 app: &Open189App<T>,
+                                 stale: Option<&str>)
+                                 -> Result<String> {
+        let _guard = self.refresh_lock.lock().unwrap();
+
+        // if the store loses a race after we've got a new token, it calls us
+        // again with the winner's token; that one is used if it's fresh, and
+        // ours is stored otherwise, without requesting yet another one
+        let mut fetched = None;
+        let stored = self.store.update(&mut |current| {
+            if let Some(current) = current {
+                if current.is_fresh(self.refresh_margin, app.clock()) &&
+                   stale != Some(&current.token.token[..]) {
+                    return Ok(current.clone());
+                }
+            }
+
+            if let Some(ref new) = fetched {
+                return Ok(new.clone());
+            }
+            let new = StoredToken::new(app.get_access_token_cc()?, app.clock());
+            debug!("got a new access token, expiring in {} s", new.token.expires_in);
+            fetched = Some(new.clone());
+            Ok(new)
+        })?;
+        Ok(stored.token.token)
+    }
+}
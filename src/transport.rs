@@ -0,0 +1,387 @@
+//! Pluggable HTTP transport for the API client.
+//!
+//! The client never talks to an HTTP library directly; every request is
+//! described by an [`HttpRequest`] and handed to a [`Transport`], which returns
+//! the raw [`HttpResponse`]. Hyper's `Client` is the default transport, but
+//! you're free to implement the trait for anything else, for example a fake
+//! transport replaying recorded responses in your tests.
+//!
+//! The asynchronous client is driven by an [`AsyncTransport`] instead. Any
+//! blocking `Transport` can be turned into one with [`CpuPoolTransport`],
+//! which runs the requests on a thread pool, blocking one of its threads per
+//! request in flight.
+//!
+//! Hyper clients honoring connect, read and write [`Timeouts`] can be made
+//! with [`hyper_client`].
+//!
+//! [`HttpRequest`]: struct.HttpRequest.html
+//! [`HttpResponse`]: struct.HttpResponse.html
+//! [`Transport`]: trait.Transport.html
+//! [`AsyncTransport`]: trait.AsyncTransport.html
+//! [`CpuPoolTransport`]: struct.CpuPoolTransport.html
+//! [`Timeouts`]: struct.Timeouts.html
+//! [`hyper_client`]: fn.hyper_client.html
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures::{BoxFuture, Future};
+use futures::sync::oneshot;
+use futures_cpupool::CpuPool;
+
+use hyper;
+use hyper::client::Client;
+use hyper::client::pool::{Config as PoolConfig, Pool};
+use hyper::header::{ContentLength, Headers};
+use hyper::method::Method;
+use hyper::net::{HttpStream, HttpsStream, NetworkConnector, Openssl, SslClient};
+use hyper::status::StatusCode;
+
+use url::Url;
+
+use super::errors::*;
+
+
+/// An outgoing HTTP request.
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    /// The request method.
+    pub method: Method,
+    /// The full request URL, including the query string if any.
+    pub url: Url,
+    /// The request headers.
+    pub headers: Headers,
+    /// The request body; empty for requests without a body.
+    pub body: Vec<u8>,
+}
+
+
+/// A received HTTP response.
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    /// The response status code.
+    pub status: StatusCode,
+    /// The response headers.
+    pub headers: Headers,
+    /// The full response body.
+    pub body: Vec<u8>,
+}
+
+
+/// An HTTP transport capable of performing a single request.
+///
+/// Implementations must be thread-safe, as a single client instance may be
+/// shared between threads.
+///
+/// # Examples
+///
+/// ```
+/// use open189::errors::Result;
+/// use open189::transport::{HttpRequest, HttpResponse, Transport};
+///
+/// struct NullTransport;
+///
+/// impl Transport for NullTransport {
+///     fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+///         unimplemented!()
+///     }
+/// }
+///
+/// let client = open189::Open189App::with_transport("app id", "secret", NullTransport);
+/// ```
+pub trait Transport: Send + Sync + 'static {
+    /// Perform the request, returning the response in full.
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse>;
+
+    /// Perform the request, giving up if connecting, or any single read or
+    /// write, takes longer than `timeout`.
+    ///
+    /// The client calls this instead of [`send`] for requests bounded by a
+    /// deadline, with the time remaining until it. The default implementation
+    /// ignores the timeout and calls [`send`], so implement this for
+    /// deadlines to be able to interrupt requests in flight.
+    ///
+    /// [`send`]: #tymethod.send
+    fn send_timeout(&self, request: HttpRequest, timeout: Duration) -> Result<HttpResponse> {
+        let _ = timeout;
+        self.send(request)
+    }
+}
+
+
+impl Transport for Client {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let HttpRequest { method, url, headers, body } = request;
+
+        let mut builder = self.request(method, url).headers(headers);
+        if !body.is_empty() {
+            builder = builder.body(&body[..]);
+        }
+
+        let response = builder.send().map_err(classify_hyper_error)?;
+        read_response(response)
+    }
+
+    /// Hyper only supports timeouts per client, so requests with a timeout
+    /// are sent over a connection of their own, outside of the pool and
+    /// bypassing the client's connector.
+    fn send_timeout(&self, request: HttpRequest, timeout: Duration) -> Result<HttpResponse> {
+        let HttpRequest { method, url, headers, body } = request;
+
+        let connector = TimeoutConnector::new(Some(timeout), Openssl::default());
+        let mut fresh = hyper::client::Request::with_connector(method, url, &connector)
+            .map_err(classify_hyper_error)?;
+        fresh.set_read_timeout(Some(timeout)).map_err(classify_io_error)?;
+        fresh.set_write_timeout(Some(timeout)).map_err(classify_io_error)?;
+        fresh.headers_mut().extend(headers.iter());
+        if !body.is_empty() {
+            fresh.headers_mut().set(ContentLength(body.len() as u64));
+        }
+
+        let mut streaming = fresh.start().map_err(classify_hyper_error)?;
+        streaming.write_all(&body).map_err(classify_io_error)?;
+        let response = streaming.send().map_err(classify_hyper_error)?;
+        read_response(response)
+    }
+}
+
+
+fn read_response(mut response: hyper::client::Response) -> Result<HttpResponse> {
+    let mut body = Vec::new();
+    response.read_to_end(&mut body).map_err(classify_io_error)?;
+
+    Ok(HttpResponse {
+        status: response.status,
+        headers: response.headers.clone(),
+        body: body,
+    })
+}
+
+
+/// Turns timeouts reported by Hyper into `ErrorKind::Timeout` errors.
+fn classify_hyper_error(e: hyper::Error) -> Error {
+    match e {
+        hyper::Error::Io(e) => classify_io_error(e),
+        e => e.into(),
+    }
+}
+
+
+fn classify_io_error(e: io::Error) -> Error {
+    if e.get_ref().map_or(false, |inner| inner.is::<ConnectTimedOut>()) {
+        return ErrorKind::Timeout(TimeoutKind::Connect).into();
+    }
+
+    match e.kind() {
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::WouldBlock => ErrorKind::Timeout(TimeoutKind::Response).into(),
+        _ => hyper::Error::Io(e).into(),
+    }
+}
+
+
+/// Timeouts for HTTP connections.
+///
+/// `None` means waiting indefinitely, which is the default.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Timeouts {
+    /// Timeout for establishing connections.
+    pub connect: Option<Duration>,
+    /// Timeout for every read from connections.
+    pub read: Option<Duration>,
+    /// Timeout for every write to connections.
+    pub write: Option<Duration>,
+}
+
+
+impl Timeouts {
+    /// Construct timeouts with the given values.
+    pub fn new(connect: Option<Duration>,
+               read: Option<Duration>,
+               write: Option<Duration>)
+               -> Timeouts {
+        Timeouts {
+            connect: connect,
+            read: read,
+            write: write,
+        }
+    }
+}
+
+
+/// Construct a pooling Hyper client honoring the given timeouts.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use open189::transport::{hyper_client, Timeouts};
+///
+/// let timeouts = Timeouts::new(Some(Duration::from_secs(5)),
+///                              Some(Duration::from_secs(10)),
+///                              Some(Duration::from_secs(10)));
+/// let client = hyper_client(&timeouts);
+/// ```
+pub fn hyper_client(timeouts: &Timeouts) -> Client {
+    let connector = TimeoutConnector::new(timeouts.connect, Openssl::default());
+    let mut client = Client::with_connector(Pool::with_connector(PoolConfig::default(), connector));
+    client.set_read_timeout(timeouts.read);
+    client.set_write_timeout(timeouts.write);
+    client
+}
+
+
+/// Marker for connect timeouts, to tell them apart from other I/O timeouts.
+#[derive(Debug)]
+struct ConnectTimedOut;
+
+
+impl fmt::Display for ConnectTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("connection timed out")
+    }
+}
+
+
+impl error::Error for ConnectTimedOut {
+    fn description(&self) -> &str {
+        "connection timed out"
+    }
+}
+
+
+/// Hyper connector with a connect timeout.
+struct TimeoutConnector<S> {
+    timeout: Option<Duration>,
+    ssl: S,
+}
+
+
+impl<S: SslClient> TimeoutConnector<S> {
+    fn new(timeout: Option<Duration>, ssl: S) -> TimeoutConnector<S> {
+        TimeoutConnector {
+            timeout: timeout,
+            ssl: ssl,
+        }
+    }
+
+    fn connect_tcp(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return TcpStream::connect((host, port)),
+        };
+
+        let mut last_error = None;
+        for addr in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                    last_error = Some(io::Error::new(io::ErrorKind::TimedOut, ConnectTimedOut));
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any address")
+        }))
+    }
+}
+
+
+impl<S: SslClient> NetworkConnector for TimeoutConnector<S> {
+    type Stream = HttpsStream<S::Stream>;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<Self::Stream> {
+        let stream = HttpStream(self.connect_tcp(host, port)?);
+        match scheme {
+            "http" => Ok(HttpsStream::Http(stream)),
+            "https" => self.ssl.wrap_client(stream, host).map(HttpsStream::Https),
+            _ => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid scheme for HTTP").into())
+            }
+        }
+    }
+}
+
+
+/// An HTTP transport performing requests asynchronously.
+pub trait AsyncTransport: Send + Sync + 'static {
+    /// Start performing the request, returning a future resolving to the
+    /// response in full.
+    fn send_async(&self, request: HttpRequest) -> BoxFuture<HttpResponse, Error>;
+
+    /// Return a future resolving once the duration has elapsed, used to wait
+    /// between retries.
+    ///
+    /// The default implementation has no timer to rely on, so it sleeps on a
+    /// thread of its own. Override it if your event loop has timers.
+    fn delay(&self, duration: Duration) -> BoxFuture<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            thread::sleep(duration);
+            let _ = tx.send(());
+        });
+        // the sender is only dropped unsent if the thread panics, which is
+        // as good as having waited
+        rx.then(|_| Ok(())).boxed()
+    }
+}
+
+
+/// Adapter running a blocking [`Transport`] on a thread pool.
+///
+/// This doesn't make the I/O non-blocking: every request is performed by the
+/// blocking transport on a pool thread, which stays blocked until the
+/// request completes. At most as many requests as the pool has threads are
+/// thus in flight at once; further ones are queued until a thread is free,
+/// and their futures resolve no sooner. Size the pool accordingly, and keep
+/// in mind that other work spawned on a shared pool competes for the same
+/// threads.
+///
+/// [`Transport`]: trait.Transport.html
+///
+/// # Examples
+///
+/// ```
+/// # extern crate futures_cpupool;
+/// # extern crate hyper;
+/// # extern crate open189;
+/// use futures_cpupool::CpuPool;
+/// use open189::transport::CpuPoolTransport;
+///
+/// # fn main() {
+/// let pool = CpuPool::new(4);
+/// let transport = CpuPoolTransport::new(hyper::client::Client::new(), pool);
+/// # }
+/// ```
+pub struct CpuPoolTransport<T: Transport> {
+    inner: Arc<T>,
+    pool: CpuPool,
+}
+
+
+impl<T: Transport> CpuPoolTransport<T> {
+    /// Wrap the blocking transport, running its requests on the given pool.
+    pub fn new(transport: T, pool: CpuPool) -> CpuPoolTransport<T> {
+        CpuPoolTransport {
+            inner: Arc::new(transport),
+            pool: pool,
+        }
+    }
+}
+
+
+impl<T: Transport + 'static> AsyncTransport for CpuPoolTransport<T> {
+    fn send_async(&self, request: HttpRequest) -> BoxFuture<HttpResponse, Error> {
+        let inner = self.inner.clone();
+        self.pool.spawn_fn(move || inner.send(request)).boxed()
+    }
+}
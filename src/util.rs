@@ -1,3 +1,5 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use chrono::*;
 use rand;
 use rand::Rng;
@@ -12,14 +14,48 @@ lazy_static! {
 }
 
 
-/// Generates a timestamp string suitable for the `open.189.cn` API.
-pub fn get_api_timestamp() -> String {
-    let utc_time = UTC::now();
+/// Formats the time as a timestamp string suitable for the `open.189.cn` API.
+pub fn get_api_timestamp(now: SystemTime) -> String {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let utc_time = UTC.timestamp(since_epoch.as_secs() as i64, since_epoch.subsec_nanos());
     let time = utc_time.with_timezone(&*ASIA_SHANGHAI);
     time.format("%Y-%m-%d %H:%M:%S").to_string()
 }
 
 
+/// Parses a timestamp string in the format of the `open.189.cn` API.
+pub fn parse_api_timestamp(timestamp: &str) -> Option<SystemTime> {
+    let time = match NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S") {
+        Ok(time) => time,
+        Err(_) => return None,
+    };
+    let secs = time.timestamp() - UTC_8_OFFSET as i64;
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+
+/// Parses the value of an HTTP `Date` header.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let time = match DateTime::parse_from_rfc2822(date) {
+        Ok(time) => time,
+        Err(_) => return None,
+    };
+    if time.timestamp() < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(time.timestamp() as u64))
+}
+
+
+/// Converts the duration to whole milliseconds, for logging.
+pub fn duration_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+
 /// Generates a sufficiently long random string for tracking OAuth requests.
 pub fn get_random_state_str() -> String {
     let mut rng = rand::thread_rng();
@@ -37,3 +73,32 @@ pub fn b64encode(input: &[u8]) -> String {
         line_length: None,
     })
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_get_api_timestamp() {
+        let now = UNIX_EPOCH + Duration::from_secs(1500000000);
+        assert_eq!(get_api_timestamp(now), "2017-07-14 10:40:00");
+        assert_eq!(parse_api_timestamp("2017-07-14 10:40:00"), Some(now));
+        assert_eq!(parse_api_timestamp("2017-07-14T10:40:00"), None);
+    }
+
+
+    #[test]
+    fn test_parse_http_date() {
+        let date = UNIX_EPOCH + Duration::from_secs(1500000000);
+        assert_eq!(parse_http_date("Fri, 14 Jul 2017 02:40:00 GMT"), Some(date));
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+
+    #[test]
+    fn test_duration_millis() {
+        assert_eq!(duration_millis(Duration::new(2, 345678901)), 2345);
+    }
+}
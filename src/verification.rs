@@ -0,0 +1,345 @@
+//! Locally generated SMS verification codes.
+//!
+//! [`VerificationCodeService`] covers the whole life cycle of verification
+//! codes sent with [`SmsCodeConfig::prepared`]: it generates the codes, sends
+//! them, remembers them in a pluggable [`CodeStore`], and checks the codes
+//! users enter against them.
+//!
+//! [`VerificationCodeService`]: struct.VerificationCodeService.html
+//! [`SmsCodeConfig::prepared`]: ../enum.SmsCodeConfig.html#method.prepared
+//! [`CodeStore`]: trait.CodeStore.html
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+
+use rand::Rng;
+use rand::os::OsRng;
+
+use super::app::Open189App;
+use super::errors::*;
+use super::msg;
+use super::phone::PhoneNumber;
+use super::req::{self, SmsCodeConfig};
+use super::transport::Transport;
+
+/// Default lifetime of verification codes.
+const DEFAULT_CODE_TTL_SECS: u64 = 300;
+/// Default number of wrong guesses allowed per code.
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+/// Length of the random salt for hashing codes.
+const SALT_LENGTH: usize = 16;
+
+
+/// A verification code awaiting verification.
+///
+/// Only a salted hash of the code is kept.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PendingCode {
+    /// Salted SHA-256 hash of the code.
+    pub code_hash: Vec<u8>,
+    /// The salt used for hashing.
+    pub salt: Vec<u8>,
+    /// The time after which the code is no longer accepted.
+    pub expires_at: SystemTime,
+    /// The number of failed verification attempts so far.
+    pub attempts: u32,
+}
+
+
+/// Storage backend for pending verification codes, keyed by phone number.
+///
+/// Implementations must be safe to share between threads.
+pub trait CodeStore: Send + Sync {
+    /// Load the pending code for the phone number, if any.
+    fn load(&self, phone: &str) -> Result<Option<PendingCode>>;
+
+    /// Store the pending code for the phone number, replacing any existing
+    /// one.
+    fn save(&self, phone: &str, code: &PendingCode) -> Result<()>;
+
+    /// Remove the pending code for the phone number, if any.
+    fn remove(&self, phone: &str) -> Result<()>;
+}
+
+
+/// In-memory code store, private to the current process.
+#[derive(Debug, Default)]
+pub struct MemoryCodeStore {
+    codes: Mutex<HashMap<String, PendingCode>>,
+}
+
+
+impl MemoryCodeStore {
+    /// Construct an empty store.
+    pub fn new() -> MemoryCodeStore {
+        Default::default()
+    }
+}
+
+
+impl CodeStore for MemoryCodeStore {
+    fn load(&self, phone: &str) -> Result<Option<PendingCode>> {
+        Ok(self.codes.lock().unwrap().get(phone).cloned())
+    }
+
+    fn save(&self, phone: &str, code: &PendingCode) -> Result<()> {
+        self.codes.lock().unwrap().insert(phone.to_string(), code.clone());
+        Ok(())
+    }
+
+    fn remove(&self, phone: &str) -> Result<()> {
+        self.codes.lock().unwrap().remove(phone);
+        Ok(())
+    }
+}
+
+
+/// Outcome of a verification attempt.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Verification {
+    /// The code is correct. It's consumed and won't be accepted again.
+    Valid,
+    /// The code is wrong.
+    Invalid,
+    /// The code has expired.
+    Expired,
+    /// Too many wrong guesses have been made; the code can no longer be
+    /// verified, and stays locked until it expires or a new one is sent.
+    TooManyAttempts,
+    /// No code has been sent to the phone number, or it's already consumed.
+    NotFound,
+}
+
+
+/// Generator, sender and verifier of SMS verification codes.
+///
+/// Codes are drawn from the operating system's secure random number
+/// generator, and are single-use: a code is discarded once it's verified or
+/// has expired. After too many wrong guesses, a code is locked until it
+/// expires, and verifying it reports `Verification::TooManyAttempts`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use open189::Open189App;
+/// use open189::verification::{Verification, VerificationCodeService};
+///
+/// # fn fetch_cached_access_token() -> &'static str { "dummy" }
+/// # fn foo() -> open189::errors::Result<()> {
+/// let app = Open189App::new("your app id here", "your app secret here");
+/// let service = VerificationCodeService::new();
+///
+/// service.send(&app, fetch_cached_access_token(), "18912345678")?;
+///
+/// // later, when the user enters the code
+/// # let code = "123456";
+/// if service.verify("18912345678", code)? == Verification::Valid {
+///     println!("verified!");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct VerificationCodeService<S: CodeStore = MemoryCodeStore> {
+    store: S,
+    ttl: Duration,
+    max_attempts: u32,
+    lock: Mutex<()>,
+}
+
+
+impl VerificationCodeService<MemoryCodeStore> {
+    /// Construct a service keeping codes in memory, with the default code
+    /// lifetime of 5 minutes and 5 attempts allowed per code.
+    pub fn new() -> VerificationCodeService<MemoryCodeStore> {
+        VerificationCodeService::with_store(MemoryCodeStore::new())
+    }
+}
+
+
+impl<S: CodeStore> VerificationCodeService<S> {
+    /// Construct a service keeping codes in the given store, with the default
+    /// code lifetime of 5 minutes and 5 attempts allowed per code.
+    pub fn with_store(store: S) -> VerificationCodeService<S> {
+        VerificationCodeService::with_store_and_limits(store,
+                                                       Duration::from_secs(DEFAULT_CODE_TTL_SECS),
+                                                       DEFAULT_MAX_ATTEMPTS)
+    }
+
+    /// Construct a service keeping codes in the given store, with the
+    /// specified code lifetime and number of attempts allowed per code.
+    pub fn with_store_and_limits(store: S,
+                                 ttl: Duration,
+                                 max_attempts: u32)
+                                 -> VerificationCodeService<S> {
+        VerificationCodeService {
+            store: store,
+            ttl: ttl,
+            max_attempts: max_attempts,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Get the code store in use.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Generate a new verification code and send it to the phone number.
+    ///
+    /// An access token is required; the per-SMS token is requested
+    /// automatically. Any code previously sent to the same phone number is
+    /// superseded once the new one is successfully sent.
+    ///
+    /// Phone numbers are normalized, so the code can be verified against the
+    /// number written in any of the forms accepted by [`PhoneNumber`].
+    ///
+    /// [`PhoneNumber`]: ../phone/struct.PhoneNumber.html
+    pub fn send<T: Transport>(&self,
+                              app: &Open189App<T>,
+                              access_token: &str,
+                              phone: &str)
+                              -> Result<msg::SentSmsCode> {
+        let phone = PhoneNumber::parse(phone)?;
+        let code = generate_code()?;
+
+        // the expiry time in the SMS is in minutes
+        let expire_minutes = (self.ttl.as_secs() + 59) / 60;
+        let config = SmsCodeConfig::prepared(phone.as_str(),
+                                             code.as_str(),
+                                             Some(expire_minutes as usize))?;
+
+        let sms_token = app.sms_get_token(access_token)?;
+        let result = app.sms_send_verification_code(access_token, sms_token.as_str(), config)?;
+
+        self.remember(phone.as_str(), &code)?;
+        Ok(result)
+    }
+
+    /// Check the code entered by the user.
+    ///
+    /// Only `Ok(Verification::Valid)` means the code is correct; in that case
+    /// it's also consumed.
+    pub fn verify(&self, phone: &str, code: &str) -> Result<Verification> {
+        let phone = match PhoneNumber::parse(phone) {
+            Ok(phone) => phone.into_string(),
+            // no code could have been sent to it
+            Err(_) => return Ok(Verification::NotFound),
+        };
+        let phone = phone.as_str();
+
+        let _guard = self.lock.lock().unwrap();
+
+        let mut pending = match self.store.load(phone)? {
+            Some(pending) => pending,
+            None => return Ok(Verification::NotFound),
+        };
+
+        if SystemTime::now() >= pending.expires_at {
+            self.store.remove(phone)?;
+            return Ok(Verification::Expired);
+        }
+        if pending.attempts >= self.max_attempts {
+            // kept until expiry, so that guessing can't simply resume
+            return Ok(Verification::TooManyAttempts);
+        }
+
+        let code_hash = hash_code(&pending.salt, phone, code);
+        if fixed_time_eq(&code_hash, &pending.code_hash) {
+            self.store.remove(phone)?;
+            return Ok(Verification::Valid);
+        }
+
+        pending.attempts += 1;
+        self.store.save(phone, &pending)?;
+        Ok(Verification::Invalid)
+    }
+
+    fn remember(&self, phone: &str, code: &str) -> Result<()> {
+        let mut rng = OsRng::new()?;
+        let salt: Vec<u8> = rng.gen_iter::<u8>().take(SALT_LENGTH).collect();
+
+        let pending = PendingCode {
+            code_hash: hash_code(&salt, phone, code),
+            salt: salt,
+            expires_at: SystemTime::now() + self.ttl,
+            attempts: 0,
+        };
+
+        let _guard = self.lock.lock().unwrap();
+        self.store.save(phone, &pending)
+    }
+}
+
+
+/// Generates a random code satisfying the API's requirements.
+fn generate_code() -> Result<String> {
+    let mut rng = OsRng::new()?;
+    Ok((0..req::SMS_CODE_LENGTH)
+        .map(|_| (b'0' + rng.gen_range(0, 10)) as char)
+        .collect())
+}
+
+
+fn hash_code(salt: &[u8], phone: &str, code: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(salt);
+    hasher.input(phone.as_bytes());
+    hasher.input(b":");
+    hasher.input(code.as_bytes());
+
+    let mut result = vec![0; hasher.output_bytes()];
+    hasher.result(&mut result);
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_generate_code() {
+        let code = generate_code().unwrap();
+        assert_eq!(code.len(), req::SMS_CODE_LENGTH);
+        assert!(code.chars().all(|ch| ch.is_digit(10)));
+    }
+
+
+    #[test]
+    fn test_verify() {
+        let service = VerificationCodeService::with_store_and_limits(MemoryCodeStore::new(),
+                                                                     Duration::from_secs(60),
+                                                                     2);
+
+        service.remember("18912345678", "123456").unwrap();
+        assert_eq!(service.verify("18912345678", "654321").unwrap(),
+                   Verification::Invalid);
+        assert_eq!(service.verify("18912345678", "123456").unwrap(),
+                   Verification::Valid);
+        // single use
+        assert_eq!(service.verify("18912345678", "123456").unwrap(),
+                   Verification::NotFound);
+
+        service.remember("18912345678", "123456").unwrap();
+        assert_eq!(service.verify("18912345678", "000000").unwrap(),
+                   Verification::Invalid);
+        assert_eq!(service.verify("18912345678", "000000").unwrap(),
+                   Verification::Invalid);
+        // locked after too many attempts, even for the right code
+        assert_eq!(service.verify("18912345678", "123456").unwrap(),
+                   Verification::TooManyAttempts);
+        assert_eq!(service.verify("18912345678", "123456").unwrap(),
+                   Verification::TooManyAttempts);
+
+        // until a new code is sent
+        service.remember("18912345678", "123456").unwrap();
+        assert_eq!(service.verify("18912345678", "123456").unwrap(),
+                   Verification::Valid);
+    }
+}